@@ -1,113 +1,386 @@
-use std::collections::VecDeque;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::time::Duration;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 
 use anyhow::Result;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Data, Sample, SampleFormat, Stream};
-use log::{info, warn};
 
-use crate::{MicMsg, SpeakerMsg};
+use crate::{
+    controls::Controls,
+    denoise::{NoiseSuppressor, SpectralDenoiser},
+    drift::DriftCompensator,
+    gain::InputGain,
+    ring::{self, Consumer, Producer},
+    rng::Rng,
+    wav::Format,
+    MicMsg,
+};
+
+pub mod device;
+pub mod echo;
+pub mod headless;
+pub mod resample;
+
+pub use device::list_devices;
+
+use device::CpalBackend;
+use echo::EchoCanceller;
+use headless::{HeadlessBackend, SinkSpec, SourceSpec};
+use resample::{MultiResampler, Quality};
+
+/// Sample rate of everything past the audio devices, dictated by Opus
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// Which audio host and devices to use, `None` meaning the default one
+#[derive(Default)]
+pub struct AudioOptions {
+    pub host: Option<String>,
+    /// Device name or index as printed by `list_devices`
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    /// How carefully to convert between device rates and 48 kHz
+    pub resample_quality: Quality,
+    /// Stand-in for the microphone, replacing the devices with the headless backend
+    pub input: Option<SourceSpec>,
+    /// Stand-in for the speakers, replacing the devices with the headless backend
+    pub output: Option<SinkSpec>,
+}
+
+/// Where microphone audio comes from and speaker audio goes to
+pub trait AudioBackend {
+    /// Format `Capture::process` will be fed
+    fn input_format(&self) -> Result<Format>;
+    /// Format `Playback::render` is asked for
+    fn output_format(&self) -> Result<Format>;
+    /// Keeps feeding `capture` and draining `playback` until `shutdown` fires
+    /// or the input runs out
+    fn run(
+        self: Box<Self>,
+        capture: Capture,
+        playback: Playback,
+        shutdown: Receiver<()>,
+    ) -> Result<()>;
+}
 
 pub fn audio_worker(
     stx: Sender<MicMsg>,
-    crx: Receiver<SpeakerMsg>,
+    playback: Consumer,
     shutdown_rx: Receiver<()>,
+    options: AudioOptions,
+    controls: Arc<Controls>,
+    channels: usize,
 ) -> Result<()> {
-    let host = cpal::default_host();
-
-    let device = host
-        .default_output_device()
-        .expect("No output device available");
-
-    let mut supported_configs_range = device
-        .supported_output_configs()
-        .expect("Error while querying configs");
-    let supported_config = supported_configs_range
-        .next()
-        .expect("No supported config?!")
-        .with_max_sample_rate();
-
-    let sample_format = supported_config.sample_format();
-
-    let config = device.default_output_config().unwrap();
-    println!("Default output config: {:?}", config);
-    let writer = match sample_format {
-        cpal::SampleFormat::F32 => audio_writer::<f32>(&device, &config.clone().into(), crx),
-        cpal::SampleFormat::I16 => audio_writer::<i16>(&device, &config.clone().into(), crx),
-        cpal::SampleFormat::U16 => audio_writer::<u16>(&device, &config.clone().into(), crx),
-    }
-    .expect("Can't run audio writer");
-
-    let config = device.default_input_config().unwrap();
-    println!("Default input config: {:?}", config);
-    let reader = match sample_format {
-        cpal::SampleFormat::F32 => audio_reader::<f32>(&device, &config.into(), stx),
-        cpal::SampleFormat::I16 => audio_reader::<i16>(&device, &config.into(), stx),
-        cpal::SampleFormat::U16 => audio_reader::<u16>(&device, &config.into(), stx),
+    let backend: Box<dyn AudioBackend> = match (&options.input, &options.output) {
+        (None, None) => Box::new(CpalBackend::new(&options)?),
+        (input, output) => Box::new(HeadlessBackend::new(
+            input.as_ref().unwrap_or(&SourceSpec::Silence),
+            output.as_ref().unwrap_or(&SinkSpec::Null),
+            channels,
+        )?),
+    };
+    let quality = options.resample_quality;
+    let (reference_tx, reference_rx) = ring::ring(ECHO_REFERENCE_RING_SIZE * channels);
+    let playback = Playback::new(
+        backend.output_format()?,
+        channels,
+        playback,
+        reference_tx,
+        quality,
+    );
+    let capture = Capture::new(
+        backend.input_format()?,
+        channels,
+        stx,
+        reference_rx,
+        quality,
+        controls,
+    );
+    backend.run(capture, playback, shutdown_rx)
+}
+
+/// Far-end audio handed from the output to the capture callback, 200 ms at 48 kHz per channel
+const ECHO_REFERENCE_RING_SIZE: usize = 9600;
+
+/// Level of the noise played when the ring runs dry, about -66 dBFS
+const COMFORT_NOISE_LEVEL: f32 = 0.0005;
+
+/// Cheap white noise so that underruns don't sound like dead air
+struct ComfortNoise {
+    rng: Rng,
+}
+
+impl ComfortNoise {
+    fn next(&mut self) -> f32 {
+        self.rng.signed() * COMFORT_NOISE_LEVEL
     }
-    .expect("Can't run audio reader");
+}
 
-    shutdown_rx.recv().expect("Can't receive shutdown msg");
+/// Playback ring fill the output keeps in reserve against scheduling hiccups, 10 ms in frames
+const PLAYBACK_MARGIN: usize = 480;
+/// How much audio the lowest ring fill is tracked over before correcting drift, 0.5 s
+const DRIFT_WINDOW: usize = 24000;
+/// Weight of each window's low-water mark in the playback drift estimate
+const PLAYBACK_DRIFT_SMOOTHING: f64 = 0.2;
 
-    Ok(())
+/// Turns the mixed 48 kHz audio from the ServCon into audio in the output's format
+pub struct Playback {
+    device_channels: usize,
+    channels: usize,
+    resampler: MultiResampler,
+    playback: Consumer,
+    /// What was played, for the echo canceller
+    reference: Producer,
+    from_srv: Vec<f32>,
+    resampled: Vec<f32>,
+    noise: ComfortNoise,
+    // The ring is filled on the mic clock and drained on the speaker clock, so
+    // its lowest fill creeps up or down unless the ratio follows the drift
+    drift: DriftCompensator,
+    low_water: usize,
+    window: usize,
 }
 
-fn audio_writer<T>(
-    device: &cpal::Device,
-    config: &cpal::StreamConfig,
-    rx: Receiver<SpeakerMsg>,
-) -> Result<Stream, anyhow::Error>
-where
-    T: 'static + cpal::Sample,
-{
-    let channels = config.channels as usize;
-    let mut ringbuf: VecDeque<f32> = VecDeque::new();
-    let write_callback = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-        while ringbuf.len() < data.len() / channels {
-            let mic_buffer = rx.recv().expect("Can't receive from channel");
-            match mic_buffer {
-                SpeakerMsg::AudioFromSrv(mic_buffer) => {
-                    ringbuf.extend(mic_buffer.iter());
-                }
-            }
+impl Playback {
+    fn new(
+        format: Format,
+        channels: usize,
+        playback: Consumer,
+        reference: Producer,
+        quality: Quality,
+    ) -> Self {
+        Self {
+            device_channels: format.channels,
+            channels,
+            resampler: MultiResampler::new(OPUS_SAMPLE_RATE, format.sample_rate, quality, channels),
+            playback,
+            reference,
+            from_srv: vec![0.0; 8192],
+            resampled: vec![0.0; 8192],
+            noise: ComfortNoise {
+                rng: Rng::new(0x2545_f491),
+            },
+            drift: DriftCompensator::new(PLAYBACK_DRIFT_SMOOTHING),
+            low_water: usize::MAX,
+            window: 0,
         }
-        for frame in data.chunks_mut(channels) {
-            let from_mic = ringbuf.pop_front().expect("Not enough smp in ringbuf");
-            for smp in frame {
-                *smp = Sample::from(&from_mic);
-            }
+    }
+
+    /// Fills interleaved `data` with what plays next
+    pub fn render(&mut self, data: &mut [f32]) {
+        let channels = self.channels;
+        let frames = data.len() / self.device_channels;
+        self.low_water = self.low_water.min(self.playback.stats().fill / channels);
+        self.window += frames;
+        if self.window >= DRIFT_WINDOW {
+            self.resampler
+                .set_correction(self.drift.update(self.low_water, PLAYBACK_MARGIN));
+            self.low_water = usize::MAX;
+            self.window = 0;
         }
-    };
-    let err_fn = |err| eprintln!("An error occurred on the output audio stream: {}", err);
-    let stream = device.build_output_stream::<T, _, _>(config, write_callback, err_fn)?;
-    stream.play().unwrap();
-    info!("Audio output stream has been started");
-    Ok(stream)
+        let needed = self.resampler.input_needed(frames) * channels;
+        if self.from_srv.len() < needed {
+            self.from_srv.resize(needed, 0.0);
+        }
+        if self.resampled.len() < frames * channels {
+            self.resampled.resize(frames * channels, 0.0);
+        }
+        let from_srv = &mut self.from_srv[..needed];
+        let read = self.playback.read(from_srv);
+        for smp in &mut from_srv[read..] {
+            *smp = self.noise.next();
+        }
+        self.reference.write(from_srv);
+        let resampled = &mut self.resampled[..frames * channels];
+        self.resampler.process_into(from_srv, resampled);
+        for (frame, from_srv) in data
+            .chunks_mut(self.device_channels)
+            .zip(resampled.chunks(channels))
+        {
+            play_frame(frame, from_srv);
+        }
+    }
 }
 
-fn audio_reader<T>(
-    device: &cpal::Device,
-    config: &cpal::StreamConfig,
+/// Reference slack or shortfall left for the echo filter to absorb, 5 ms in frames
+const REFERENCE_TOLERANCE: usize = 240;
+
+/// Turns audio from the input into cleaned up 48 kHz audio for the ServCon
+pub struct Capture {
+    device_channels: usize,
+    channels: usize,
+    resampler: MultiResampler,
+    /// What was played, for the echo canceller
+    reference: Consumer,
+    /// Lowest reference fill beyond what the next read takes, over the current window
+    reference_slack: usize,
+    reference_window: usize,
+    /// Reference samples that were missing on underruns, skipped once they arrive
+    reference_debt: usize,
+    echo: Vec<EchoCanceller>,
+    denoisers: Vec<Box<dyn NoiseSuppressor>>,
+    gain: InputGain,
+    controls: Arc<Controls>,
     tx: Sender<MicMsg>,
-) -> Result<Stream, anyhow::Error>
-where
-    T: 'static + cpal::Sample,
-{
-    let channels = config.channels as usize;
-    let read_callback = move |data: &[T], _: &cpal::InputCallbackInfo| {
+}
+
+impl Capture {
+    fn new(
+        format: Format,
+        channels: usize,
+        tx: Sender<MicMsg>,
+        reference: Consumer,
+        quality: Quality,
+        controls: Arc<Controls>,
+    ) -> Self {
+        Self {
+            device_channels: format.channels,
+            channels,
+            resampler: MultiResampler::new(format.sample_rate, OPUS_SAMPLE_RATE, quality, channels),
+            reference,
+            reference_slack: usize::MAX,
+            reference_window: 0,
+            reference_debt: 0,
+            echo: (0..channels).map(|_| EchoCanceller::new()).collect(),
+            denoisers: (0..channels)
+                .map(|_| Box::new(SpectralDenoiser::new()) as Box<dyn NoiseSuppressor>)
+                .collect(),
+            gain: InputGain::new(controls.clone(), channels),
+            controls,
+            tx,
+        }
+    }
+
+    /// Processes interleaved `data` and sends it on
+    pub fn process(&mut self, data: &[f32]) {
+        let channels = self.channels;
+        let mut captured = vec![];
+        for frame in data.chunks(self.device_channels) {
+            capture_frame(frame, channels, &mut captured);
+        }
         let mut mic_buffer = vec![];
-        for frame in data.chunks(channels) {
-            // Mixdown to mono and amplify by 4.0
-            let sum = frame.iter().map(|smp| smp.to_f32()).sum::<f32>() * 4.0;
-            mic_buffer.push(sum);
+        self.resampler.process(&captured, &mut mic_buffer);
+        let mut far_end = vec![0.0; mic_buffer.len()];
+        self.align_reference(far_end.len());
+        let read = self.reference.read(&mut far_end);
+        self.reference_debt = (self.reference_debt + far_end.len() - read)
+            .min(REFERENCE_TOLERANCE * channels);
+        // Every mic channel hears every speaker, so each is cancelled against their mix
+        let far_end: Vec<f32> = far_end
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        let mut planes = deinterleave(&mic_buffer, channels);
+        let controls = &self.controls;
+        for ((plane, echo), denoiser) in planes
+            .iter_mut()
+            .zip(&mut self.echo)
+            .zip(&mut self.denoisers)
+        {
+            echo.process(plane, &far_end, controls.echo_cancel());
+            // Denoise before the AGC so that it doesn't chase the noise floor
+            denoiser.process(plane, controls.denoise());
         }
-        tx.send(MicMsg::AudioFromMic(mic_buffer))
-            .expect("Can't send mic data over channel");
-    };
-    let err_fn = |err| eprintln!("An error occurred on the output audio stream: {}", err);
-    let stream = device.build_input_stream::<T, _, _>(config, read_callback, err_fn)?;
-    stream.play().unwrap();
-    info!("Audio input stream has been started");
-    Ok(stream)
+        let mut mic_buffer = interleave(&planes);
+        self.gain.process(&mut mic_buffer);
+        // The ServCon stops first on shutdown, the input may run a bit longer
+        let _ = self.tx.send(MicMsg::AudioFromMic(mic_buffer));
+    }
+
+    /// Keeps the reference a fixed distance from the mic. It's read contiguously
+    /// so that the echo path the filter models stays put, and only moved to
+    /// make up for underruns or, once a window, to drop the slack built up
+    /// behind the freshest far-end audio. Reading further behind would pair
+    /// the mic with audio whose echo it already picked up.
+    fn align_reference(&mut self, len: usize) {
+        let channels = self.channels;
+        let reference = &self.reference;
+        let fill = reference.stats().fill;
+        let debt = self.reference_debt.min(fill);
+        reference.skip(debt);
+        self.reference_debt -= debt;
+        self.reference_slack = self.reference_slack.min((fill - debt).saturating_sub(len));
+        self.reference_window += len / channels;
+        if self.reference_window >= DRIFT_WINDOW {
+            if self.reference_slack > REFERENCE_TOLERANCE * channels {
+                reference.skip(self.reference_slack - self.reference_slack % channels);
+            }
+            self.reference_slack = usize::MAX;
+            self.reference_window = 0;
+        }
+    }
+
+    /// Tells the ServCon that the input has run out
+    pub fn finish(self) {
+        // The ServCon may be gone already, which is just as well
+        let _ = self.tx.send(MicMsg::Shutdown);
+    }
+}
+
+/// Maps one frame of the session's channels onto the device's. Mono is copied
+/// to every speaker, stereo goes to the first two and is mixed down for a mono device.
+fn play_frame(frame: &mut [f32], from_srv: &[f32]) {
+    match (from_srv, frame.len()) {
+        ([mono], _) => frame.iter_mut().for_each(|smp| *smp = *mono),
+        ([left, right], 1) => frame[0] = (left + right) / 2.0,
+        ([left, right], _) => {
+            frame[0] = *left;
+            frame[1] = *right;
+            frame[2..].iter_mut().for_each(|smp| *smp = 0.0);
+        }
+        _ => unreachable!("Sessions are mono or stereo"),
+    }
+}
+
+/// Maps one frame of the device's channels onto the session's. Mono sums every
+/// input channel, stereo takes the first two and duplicates a mono device.
+fn capture_frame(frame: &[f32], channels: usize, captured: &mut Vec<f32>) {
+    if channels == 1 {
+        captured.push(frame.iter().sum());
+    } else {
+        captured.push(frame[0]);
+        captured.push(frame[1.min(frame.len() - 1)]);
+    }
+}
+
+fn deinterleave(buf: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    (0..channels)
+        .map(|channel| {
+            buf.iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect()
+        })
+        .collect()
+}
+
+fn interleave(planes: &[Vec<f32>]) -> Vec<f32> {
+    let channels = planes.len();
+    (0..planes[0].len() * channels)
+        .map(|n| planes[n % channels][n / channels])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_playback_channels() {
+        let mut frame = [1.0; 4];
+        play_frame(&mut frame, &[0.5]);
+        assert_eq!(frame, [0.5; 4]);
+        play_frame(&mut frame, &[0.25, 0.75]);
+        assert_eq!(frame, [0.25, 0.75, 0.0, 0.0]);
+        let mut frame = [0.0];
+        play_frame(&mut frame, &[0.25, 0.75]);
+        assert_eq!(frame, [0.5]);
+    }
+
+    #[test]
+    fn maps_capture_channels() {
+        let mut captured = vec![];
+        capture_frame(&[0.25, 0.5], 1, &mut captured);
+        capture_frame(&[0.25, 0.5, 0.75], 2, &mut captured);
+        capture_frame(&[0.125], 2, &mut captured);
+        assert_eq!(captured, [0.75, 0.25, 0.5, 0.125, 0.125]);
+    }
 }
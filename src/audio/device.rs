@@ -0,0 +1,208 @@
+use std::sync::mpsc::Receiver;
+
+use anyhow::{Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Device, Host, Sample, SampleFormat, Stream};
+use log::info;
+
+use super::{AudioBackend, AudioOptions, Capture, Playback};
+use crate::wav::Format;
+
+fn select_host(name: Option<&str>) -> Result<Host> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .with_context(|| format!("Audio host {} is not available", name))?;
+    Ok(cpal::host_from_id(id)?)
+}
+
+/// Finds a device by its index or name, preferring an exact name match
+fn select_device(devices: Vec<Device>, spec: &str) -> Result<Device> {
+    if let Ok(index) = spec.parse::<usize>() {
+        return devices
+            .into_iter()
+            .nth(index)
+            .with_context(|| format!("No audio device #{}", index));
+    }
+    let names: Vec<String> = devices
+        .iter()
+        .map(|device| device.name().unwrap_or_default())
+        .collect();
+    let spec_lower = spec.to_lowercase();
+    let position = names
+        .iter()
+        .position(|name| name == spec)
+        .or_else(|| {
+            names
+                .iter()
+                .position(|name| name.to_lowercase().contains(&spec_lower))
+        })
+        .with_context(|| format!("No audio device matches {:?}", spec))?;
+    Ok(devices.into_iter().nth(position).expect("Device is gone"))
+}
+
+fn input_device(host: &Host, spec: Option<&str>) -> Result<Device> {
+    match spec {
+        Some(spec) => select_device(host.input_devices()?.collect(), spec),
+        None => host
+            .default_input_device()
+            .context("No input device available"),
+    }
+}
+
+fn output_device(host: &Host, spec: Option<&str>) -> Result<Device> {
+    match spec {
+        Some(spec) => select_device(host.output_devices()?.collect(), spec),
+        None => host
+            .default_output_device()
+            .context("No output device available"),
+    }
+}
+
+/// Prints the available hosts and the devices of the selected one
+pub fn list_devices(options: &AudioOptions) -> Result<()> {
+    let hosts: Vec<&str> = cpal::available_hosts()
+        .into_iter()
+        .map(|id| id.name())
+        .collect();
+    println!("Hosts: {}", hosts.join(", "));
+
+    let host = select_host(options.host.as_deref())?;
+    println!("Using host {}", host.id().name());
+
+    println!("Input devices:");
+    for (index, device) in host.input_devices()?.enumerate() {
+        let config = device
+            .default_input_config()
+            .map(|config| format!("{:?}", config))
+            .unwrap_or_else(|err| err.to_string());
+        println!("  {}: {} ({})", index, device.name()?, config);
+    }
+
+    println!("Output devices:");
+    for (index, device) in host.output_devices()?.enumerate() {
+        let config = device
+            .default_output_config()
+            .map(|config| format!("{:?}", config))
+            .unwrap_or_else(|err| err.to_string());
+        println!("  {}: {} ({})", index, device.name()?, config);
+    }
+
+    Ok(())
+}
+
+/// Sound card input and output through cpal
+pub struct CpalBackend {
+    input: Device,
+    output: Device,
+}
+
+impl CpalBackend {
+    pub fn new(options: &AudioOptions) -> Result<Self> {
+        let host = select_host(options.host.as_deref())?;
+        Ok(Self {
+            input: input_device(&host, options.input_device.as_deref())?,
+            output: output_device(&host, options.output_device.as_deref())?,
+        })
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn input_format(&self) -> Result<Format> {
+        let config = self.input.default_input_config()?;
+        Ok(Format {
+            sample_rate: config.sample_rate().0,
+            channels: config.channels() as usize,
+        })
+    }
+
+    fn output_format(&self) -> Result<Format> {
+        let config = self.output.default_output_config()?;
+        Ok(Format {
+            sample_rate: config.sample_rate().0,
+            channels: config.channels() as usize,
+        })
+    }
+
+    fn run(
+        self: Box<Self>,
+        capture: Capture,
+        playback: Playback,
+        shutdown: Receiver<()>,
+    ) -> Result<()> {
+        let config = self.output.default_output_config()?;
+        println!(
+            "Output device {}, config: {:?}",
+            self.output.name()?,
+            config
+        );
+        let _writer = match config.sample_format() {
+            SampleFormat::F32 => audio_writer::<f32>(&self.output, &config.into(), playback),
+            SampleFormat::I16 => audio_writer::<i16>(&self.output, &config.into(), playback),
+            SampleFormat::U16 => audio_writer::<u16>(&self.output, &config.into(), playback),
+        }
+        .context("Can't run audio writer")?;
+
+        let config = self.input.default_input_config()?;
+        println!("Input device {}, config: {:?}", self.input.name()?, config);
+        let _reader = match config.sample_format() {
+            SampleFormat::F32 => audio_reader::<f32>(&self.input, &config.into(), capture),
+            SampleFormat::I16 => audio_reader::<i16>(&self.input, &config.into(), capture),
+            SampleFormat::U16 => audio_reader::<u16>(&self.input, &config.into(), capture),
+        }
+        .context("Can't run audio reader")?;
+
+        shutdown.recv().expect("Can't receive shutdown msg");
+
+        Ok(())
+    }
+}
+
+fn audio_writer<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut playback: Playback,
+) -> Result<Stream, anyhow::Error>
+where
+    T: 'static + cpal::Sample,
+{
+    let mut buf: Vec<f32> = vec![0.0; 8192];
+    let write_callback = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        if buf.len() < data.len() {
+            buf.resize(data.len(), 0.0);
+        }
+        let buf = &mut buf[..data.len()];
+        playback.render(buf);
+        for (smp, played) in data.iter_mut().zip(buf.iter()) {
+            *smp = Sample::from(played);
+        }
+    };
+    let err_fn = |err| eprintln!("An error occurred on the output audio stream: {}", err);
+    let stream = device.build_output_stream::<T, _, _>(config, write_callback, err_fn)?;
+    stream.play().unwrap();
+    info!("Audio output stream has been started");
+    Ok(stream)
+}
+
+fn audio_reader<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut capture: Capture,
+) -> Result<Stream, anyhow::Error>
+where
+    T: 'static + cpal::Sample,
+{
+    let read_callback = move |data: &[T], _: &cpal::InputCallbackInfo| {
+        let captured: Vec<f32> = data.iter().map(|smp| smp.to_f32()).collect();
+        capture.process(&captured);
+    };
+    let err_fn = |err| eprintln!("An error occurred on the input audio stream: {}", err);
+    let stream = device.build_input_stream::<T, _, _>(config, read_callback, err_fn)?;
+    stream.play().unwrap();
+    info!("Audio input stream has been started");
+    Ok(stream)
+}
@@ -0,0 +1,327 @@
+use std::collections::VecDeque;
+
+use crate::fft::Fft;
+
+/// Samples processed at once, about 5.3 ms at 48 kHz
+const BLOCK: usize = 256;
+const FFT_SIZE: usize = 2 * BLOCK;
+/// Filter partitions, together covering an echo tail of about 85 ms
+const PARTITIONS: usize = 16;
+/// Adaptation step of the normalized frequency-domain LMS
+const STEP: f32 = 0.5;
+/// Keeps the step bounded while the far end is quiet
+const REGULARIZATION: f32 = 1e-3;
+/// Near end louder than this share of the loudest recent far-end block means both sides are talking
+const DOUBLE_TALK_THRESHOLD: f32 = 0.5;
+/// Blocks adaptation stays frozen for after double talk, about 100 ms
+const DOUBLE_TALK_HANGOVER: u32 = 19;
+
+/// Complex spectrum of one `FFT_SIZE` frame
+#[derive(Clone)]
+struct Spectrum {
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl Spectrum {
+    fn zero() -> Self {
+        Self {
+            re: vec![0.0; FFT_SIZE],
+            im: vec![0.0; FFT_SIZE],
+        }
+    }
+}
+
+/// Removes the far-end signal picked up by the microphone, modelling the echo
+/// path with a partitioned-block frequency-domain adaptive filter.
+/// Adaptation pauses while the near end talks so that it isn't cancelled too.
+pub struct EchoCanceller {
+    fft: Fft,
+    /// Reference block before the current one, the first half of every frame
+    last_reference: Vec<f32>,
+    /// Spectra of the latest reference frames, newest first
+    history: VecDeque<Spectrum>,
+    /// Frequency response of every partition of the echo path
+    weights: Vec<Spectrum>,
+    /// RMS levels of the reference blocks the filter currently spans
+    reference_levels: VecDeque<f32>,
+    hangover: u32,
+    mic_in: Vec<f32>,
+    reference_in: Vec<f32>,
+    output: VecDeque<f32>,
+    scratch: Spectrum,
+}
+
+impl EchoCanceller {
+    pub fn new() -> Self {
+        Self {
+            fft: Fft::new(FFT_SIZE),
+            last_reference: vec![0.0; BLOCK],
+            history: vec![Spectrum::zero(); PARTITIONS].into(),
+            weights: vec![Spectrum::zero(); PARTITIONS],
+            reference_levels: vec![0.0; PARTITIONS].into(),
+            hangover: 0,
+            mic_in: Vec::with_capacity(BLOCK),
+            reference_in: Vec::with_capacity(BLOCK),
+            // Primed so that there is always a full buffer to hand out
+            output: vec![0.0; BLOCK].into(),
+            scratch: Spectrum::zero(),
+        }
+    }
+
+    /// Cancels echo of `reference`, the far-end signal sent to the speakers, from
+    /// `mic` in place. Both are mono 48 kHz and as long as each other. With
+    /// `cancel` off the microphone passes through, but the filter keeps adapting.
+    pub fn process(&mut self, mic: &mut [f32], reference: &[f32], cancel: bool) {
+        for (smp, &reference) in mic.iter_mut().zip(reference) {
+            self.mic_in.push(*smp);
+            self.reference_in.push(reference);
+            if self.mic_in.len() == BLOCK {
+                self.block(cancel);
+                self.mic_in.clear();
+                self.reference_in.clear();
+            }
+            *smp = self.output.pop_front().unwrap_or(0.0);
+        }
+    }
+
+    fn block(&mut self, cancel: bool) {
+        // Overlap-save frame of the last two reference blocks
+        let mut frame = self.history.pop_back().expect("History is empty");
+        frame.re[..BLOCK].copy_from_slice(&self.last_reference);
+        frame.re[BLOCK..].copy_from_slice(&self.reference_in);
+        frame.im.iter_mut().for_each(|im| *im = 0.0);
+        self.fft.forward(&mut frame.re, &mut frame.im);
+        self.history.push_front(frame);
+        self.last_reference.copy_from_slice(&self.reference_in);
+
+        // Echo estimate
+        let estimate = &mut self.scratch;
+        estimate.re.iter_mut().for_each(|re| *re = 0.0);
+        estimate.im.iter_mut().for_each(|im| *im = 0.0);
+        for (x, w) in self.history.iter().zip(&self.weights) {
+            for k in 0..FFT_SIZE {
+                estimate.re[k] += x.re[k] * w.re[k] - x.im[k] * w.im[k];
+                estimate.im[k] += x.re[k] * w.im[k] + x.im[k] * w.re[k];
+            }
+        }
+        self.fft.inverse(&mut estimate.re, &mut estimate.im);
+        let error: Vec<f32> = self
+            .mic_in
+            .iter()
+            .zip(&estimate.re[BLOCK..])
+            .map(|(mic, echo)| mic - echo)
+            .collect();
+
+        if cancel {
+            self.output.extend(&error);
+        } else {
+            self.output.extend(&self.mic_in);
+        }
+
+        // Geigel-style double-talk detection, on block levels rather than
+        // single peaks which noise-like signals cross all the time
+        let level =
+            |buf: &[f32]| (buf.iter().map(|smp| smp * smp).sum::<f32>() / BLOCK as f32).sqrt();
+        self.reference_levels.pop_back();
+        self.reference_levels.push_front(level(&self.reference_in));
+        let far_level = self.reference_levels.iter().fold(0.0f32, |a, &b| a.max(b));
+        if level(&self.mic_in) > DOUBLE_TALK_THRESHOLD * far_level {
+            self.hangover = DOUBLE_TALK_HANGOVER;
+        }
+        if self.hangover > 0 {
+            self.hangover -= 1;
+            return;
+        }
+        self.adapt(&error);
+    }
+
+    fn adapt(&mut self, error: &[f32]) {
+        let mut error_spectrum = Spectrum::zero();
+        error_spectrum.re[BLOCK..].copy_from_slice(error);
+        self.fft
+            .forward(&mut error_spectrum.re, &mut error_spectrum.im);
+
+        let mut power = vec![REGULARIZATION; FFT_SIZE];
+        for x in &self.history {
+            for (power, (re, im)) in power.iter_mut().zip(x.re.iter().zip(&x.im)) {
+                *power += re * re + im * im;
+            }
+        }
+
+        let gradient = &mut self.scratch;
+        for (x, w) in self.history.iter().zip(self.weights.iter_mut()) {
+            // conj(X) * E, normalized per bin
+            for (k, power) in power.iter().enumerate() {
+                let e = (error_spectrum.re[k], error_spectrum.im[k]);
+                let scale = STEP / power;
+                gradient.re[k] = (x.re[k] * e.0 + x.im[k] * e.1) * scale;
+                gradient.im[k] = (x.re[k] * e.1 - x.im[k] * e.0) * scale;
+            }
+            // Keep the partition's impulse response one block long, otherwise
+            // circular convolution leaks into the estimate
+            self.fft.inverse(&mut gradient.re, &mut gradient.im);
+            gradient.re[BLOCK..].iter_mut().for_each(|re| *re = 0.0);
+            gradient.im.iter_mut().for_each(|im| *im = 0.0);
+            self.fft.forward(&mut gradient.re, &mut gradient.im);
+            for k in 0..FFT_SIZE {
+                w.re[k] += gradient.re[k];
+                w.im[k] += gradient.im[k];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        audio::{resample::Quality, Capture, ECHO_REFERENCE_RING_SIZE},
+        controls::{ControlOptions, Controls},
+        gain::GainOptions,
+        ring,
+        rng::Rng,
+        wav::Format,
+        MicMsg,
+    };
+
+    const RATE: usize = 48000;
+
+    fn noise(len: usize, seed: u32, level: f32) -> Vec<f32> {
+        let mut rng = Rng::new(seed);
+        (0..len).map(|_| rng.signed() * level).collect()
+    }
+
+    /// Room-like echo path: 20 ms of delay, then a decaying tail 12 dB down
+    fn echo_path() -> Vec<f32> {
+        let delay = RATE / 50;
+        let mut path = vec![0.0; delay + 300];
+        for (i, tap) in path[delay..].iter_mut().enumerate() {
+            let sign = if i % 3 == 0 { 1.0 } else { -0.5 };
+            *tap = 0.07 * sign * 0.98f32.powi(i as i32);
+        }
+        path
+    }
+
+    fn convolve(signal: &[f32], path: &[f32]) -> Vec<f32> {
+        (0..signal.len())
+            .map(|n| {
+                path.iter()
+                    .enumerate()
+                    .take(n + 1)
+                    .map(|(i, tap)| tap * signal[n - i])
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn power_db(buf: &[f32]) -> f32 {
+        let power = buf.iter().map(|smp| smp * smp).sum::<f32>() / buf.len() as f32;
+        10.0 * power.log10()
+    }
+
+    fn cancel(mic: &[f32], far: &[f32]) -> Vec<f32> {
+        let mut canceller = EchoCanceller::new();
+        let mut output = mic.to_vec();
+        for (mic, far) in output.chunks_mut(480).zip(far.chunks(480)) {
+            canceller.process(mic, far, true);
+        }
+        output
+    }
+
+    #[test]
+    fn cancels_synthetic_echo() {
+        let far = noise(3 * RATE, 0x2545_f491, 0.3);
+        let echo = convolve(&far, &echo_path());
+        let output = cancel(&echo, &far);
+
+        // Echo return loss enhancement over the last second
+        let last = 2 * RATE..3 * RATE;
+        let erle = power_db(&echo[last.start - BLOCK..last.end - BLOCK]) - power_db(&output[last]);
+        assert!(erle > 30.0, "ERLE {:.1} dB", erle);
+    }
+
+    #[test]
+    fn keeps_near_end_during_double_talk() {
+        let far = noise(4 * RATE, 0x2545_f491, 0.3);
+        let echo = convolve(&far, &echo_path());
+        // The near end starts talking once the filter has converged
+        let near: Vec<f32> = (0..4 * RATE)
+            .map(|n| {
+                if n < 3 * RATE {
+                    0.0
+                } else {
+                    (2.0 * std::f32::consts::PI * 300.0 * n as f32 / RATE as f32).sin() * 0.3
+                }
+            })
+            .collect();
+        let mic: Vec<f32> = echo.iter().zip(&near).map(|(e, n)| e + n).collect();
+        let output = cancel(&mic, &far);
+
+        let talk = 3 * RATE + RATE / 10..4 * RATE;
+        let residual: Vec<f32> = output[talk.clone()]
+            .iter()
+            .zip(&near[talk.start - BLOCK..talk.end - BLOCK])
+            .map(|(out, near)| out - near)
+            .collect();
+        let near_db = power_db(&near[talk.start - BLOCK..talk.end - BLOCK]);
+        assert!(
+            power_db(&residual) < near_db - 20.0,
+            "residual {:.1} dB, near end {:.1} dB",
+            power_db(&residual),
+            near_db
+        );
+    }
+
+    #[test]
+    fn cancels_through_capture_with_mismatched_blocks() {
+        // The output plays blocks of its own size, each written a block ahead
+        const OUTPUT_BLOCK: usize = 1114;
+        const MIC_BLOCK: usize = 480;
+        let far = noise(3 * RATE, 0x2545_f491, 0.3);
+        let echo = convolve(&far, &echo_path());
+
+        let options = ControlOptions {
+            gain: GainOptions {
+                gain_db: 0.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (reference_tx, reference_rx) = ring::ring(ECHO_REFERENCE_RING_SIZE);
+        let (tx, rx) = mpsc::channel();
+        let mut capture = Capture::new(
+            Format {
+                sample_rate: RATE as u32,
+                channels: 1,
+            },
+            1,
+            tx,
+            reference_rx,
+            Quality::Low,
+            Controls::new(&options),
+        );
+        let mut written = 0;
+        for start in (0..echo.len()).step_by(MIC_BLOCK) {
+            let end = start + MIC_BLOCK;
+            while written < (end + OUTPUT_BLOCK).min(far.len()) {
+                let block = &far[written..(written + OUTPUT_BLOCK).min(far.len())];
+                written += reference_tx.write(block);
+            }
+            capture.process(&echo[start..end]);
+        }
+        let output: Vec<f32> = rx
+            .try_iter()
+            .flat_map(|msg| match msg {
+                MicMsg::AudioFromMic(audio) => audio,
+                _ => vec![],
+            })
+            .collect();
+
+        let last = 2 * RATE..output.len();
+        let erle = power_db(&echo[last.start - BLOCK..last.end - BLOCK]) - power_db(&output[last]);
+        assert!(erle > 20.0, "ERLE {:.1} dB", erle);
+    }
+}
@@ -0,0 +1,384 @@
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use log::info;
+
+use super::{AudioBackend, Capture, Playback, OPUS_SAMPLE_RATE};
+use crate::rng::Rng;
+use crate::wav::{self, Format, WavWriter};
+
+/// Audio moved per step of the headless backend, 10 ms
+const BLOCK: Duration = Duration::from_millis(10);
+const BLOCKS_PER_SECOND: u32 = 100;
+
+const TONE_LEVEL: f32 = 0.25;
+const NOISE_LEVEL: f32 = 0.1;
+const DEFAULT_TONE_HZ: f32 = 440.0;
+
+/// What the headless backend captures instead of a microphone
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceSpec {
+    /// Plays a WAV file once, then the client leaves
+    Wav(PathBuf),
+    /// Sine tone of the given frequency
+    Tone(f32),
+    /// White noise
+    Noise,
+    Silence,
+}
+
+impl FromStr for SourceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("wav", path)) => Ok(SourceSpec::Wav(path.into())),
+            Some(("tone", hz)) => match hz.parse() {
+                Ok(hz) if hz > 0.0 && hz < OPUS_SAMPLE_RATE as f32 / 2.0 => {
+                    Ok(SourceSpec::Tone(hz))
+                }
+                _ => bail!("Bad tone frequency {:?}", hz),
+            },
+            None if s == "tone" => Ok(SourceSpec::Tone(DEFAULT_TONE_HZ)),
+            None if s == "noise" => Ok(SourceSpec::Noise),
+            None if s == "silence" => Ok(SourceSpec::Silence),
+            _ => bail!(
+                "Unknown input {:?}, expected wav:<PATH>, tone[:<HZ>], noise or silence",
+                s
+            ),
+        }
+    }
+}
+
+/// Where the headless backend puts what would have been played
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkSpec {
+    Wav(PathBuf),
+    /// Drops everything
+    Null,
+}
+
+impl FromStr for SinkSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("wav", path)) => Ok(SinkSpec::Wav(path.into())),
+            None if s == "null" => Ok(SinkSpec::Null),
+            _ => bail!("Unknown output {:?}, expected wav:<PATH> or null", s),
+        }
+    }
+}
+
+trait Source: Send {
+    fn format(&self) -> Format;
+    /// Fills `buf`, returns false once there is nothing left
+    fn read(&mut self, buf: &mut [f32]) -> bool;
+}
+
+trait Sink: Send {
+    fn write(&mut self, buf: &[f32]) -> Result<()>;
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+struct WavSource {
+    format: Format,
+    samples: Vec<f32>,
+    position: usize,
+}
+
+impl Source for WavSource {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> bool {
+        if self.position >= self.samples.len() {
+            return false;
+        }
+        let rest = &self.samples[self.position..];
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        buf[len..].iter_mut().for_each(|smp| *smp = 0.0);
+        self.position += len;
+        true
+    }
+}
+
+/// Endless mono signal at 48 kHz
+struct Generator {
+    spec: SourceSpec,
+    /// Samples generated so far, for the tone
+    time: u64,
+    /// For the noise
+    rng: Rng,
+}
+
+impl Source for Generator {
+    fn format(&self) -> Format {
+        Format {
+            sample_rate: OPUS_SAMPLE_RATE,
+            channels: 1,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> bool {
+        for smp in buf {
+            *smp = match self.spec {
+                SourceSpec::Tone(hz) => {
+                    let t = self.time as f64 / OPUS_SAMPLE_RATE as f64;
+                    (2.0 * PI as f64 * hz as f64 * t).sin() as f32 * TONE_LEVEL
+                }
+                SourceSpec::Noise => self.rng.signed() * NOISE_LEVEL,
+                _ => 0.0,
+            };
+            self.time += 1;
+        }
+        true
+    }
+}
+
+struct WavSink(WavWriter);
+
+impl Sink for WavSink {
+    fn write(&mut self, buf: &[f32]) -> Result<()> {
+        self.0.write(buf)
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.0.finish()
+    }
+}
+
+struct NullSink;
+
+impl Sink for NullSink {
+    fn write(&mut self, _buf: &[f32]) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Files and synthetic signals in place of a sound card, so that the client
+/// runs without one. Paced in real time like a device would be.
+pub struct HeadlessBackend {
+    source: Box<dyn Source>,
+    sink: Box<dyn Sink>,
+    output_format: Format,
+}
+
+impl HeadlessBackend {
+    /// `channels` is what the output gets written with, at 48 kHz
+    pub fn new(source: &SourceSpec, sink: &SinkSpec, channels: usize) -> Result<Self> {
+        let source: Box<dyn Source> = match source {
+            SourceSpec::Wav(path) => {
+                let (format, samples) = wav::read(path)?;
+                // Anything slower makes for empty blocks, which never get through the file
+                if format.sample_rate < BLOCKS_PER_SECOND {
+                    bail!(
+                        "{} is at {} Hz, at least {} Hz is needed",
+                        path.display(),
+                        format.sample_rate,
+                        BLOCKS_PER_SECOND
+                    );
+                }
+                Box::new(WavSource {
+                    format,
+                    samples,
+                    position: 0,
+                })
+            }
+            spec => Box::new(Generator {
+                spec: spec.clone(),
+                time: 0,
+                rng: Rng::new(0x1234_5678),
+            }),
+        };
+        let output_format = Format {
+            sample_rate: OPUS_SAMPLE_RATE,
+            channels,
+        };
+        let sink: Box<dyn Sink> = match sink {
+            SinkSpec::Wav(path) => Box::new(WavSink(WavWriter::create(path, output_format)?)),
+            SinkSpec::Null => Box::new(NullSink),
+        };
+        Ok(Self {
+            source,
+            sink,
+            output_format,
+        })
+    }
+}
+
+impl AudioBackend for HeadlessBackend {
+    fn input_format(&self) -> Result<Format> {
+        Ok(self.source.format())
+    }
+
+    fn output_format(&self) -> Result<Format> {
+        Ok(self.output_format)
+    }
+
+    fn run(
+        mut self: Box<Self>,
+        mut capture: Capture,
+        mut playback: Playback,
+        shutdown: Receiver<()>,
+    ) -> Result<()> {
+        let input_format = self.source.format();
+        let mut input = vec![
+            0.0;
+            (input_format.sample_rate / BLOCKS_PER_SECOND) as usize
+                * input_format.channels
+        ];
+        let output_format = self.output_format;
+        let mut output = vec![
+            0.0;
+            (output_format.sample_rate / BLOCKS_PER_SECOND) as usize
+                * output_format.channels
+        ];
+        info!(
+            "Headless audio, input {:?}, output {:?}",
+            input_format, output_format
+        );
+
+        let start = Instant::now();
+        let mut blocks = 0;
+        while shutdown.try_recv().is_err() {
+            if !self.source.read(&mut input) {
+                info!("Audio input has run out");
+                capture.finish();
+                break;
+            }
+            capture.process(&input);
+            playback.render(&mut output);
+            self.sink.write(&output)?;
+
+            blocks += 1;
+            let next = start + BLOCK * blocks;
+            if let Some(wait) = next.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        }
+        self.sink.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::audio::{resample::Quality, Capture, Playback};
+    use crate::controls::{ControlOptions, Controls};
+    use crate::{ring, MicMsg};
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(
+            "tone".parse::<SourceSpec>().unwrap(),
+            SourceSpec::Tone(440.0)
+        );
+        assert_eq!(
+            "tone:1000".parse::<SourceSpec>().unwrap(),
+            SourceSpec::Tone(1000.0)
+        );
+        assert_eq!(
+            "wav:a.wav".parse::<SourceSpec>().unwrap(),
+            SourceSpec::Wav("a.wav".into())
+        );
+        assert_eq!("null".parse::<SinkSpec>().unwrap(), SinkSpec::Null);
+        assert!("tone:30000".parse::<SourceSpec>().is_err());
+        assert!("speakers".parse::<SinkSpec>().is_err());
+    }
+
+    /// Plays a WAV file in as the mic while recording what the ServCon would have
+    /// played, the way an end-to-end run does
+    #[test]
+    fn runs_files_through_the_client() {
+        let dir = std::env::temp_dir();
+        let input_path = dir.join(format!("discurse-in-{}.wav", std::process::id()));
+        let output_path = dir.join(format!("discurse-out-{}.wav", std::process::id()));
+        let input_format = Format {
+            sample_rate: 44100,
+            channels: 2,
+        };
+        let tone: Vec<f32> = (0..44100 / 5)
+            .flat_map(|n| {
+                let smp = (2.0 * PI * 440.0 * n as f32 / 44100.0).sin() * 0.25;
+                [smp, smp]
+            })
+            .collect();
+        wav::write(&input_path, input_format, &tone).expect("Can't write WAV");
+
+        let backend = HeadlessBackend::new(
+            &SourceSpec::Wav(input_path.clone()),
+            &SinkSpec::Wav(output_path.clone()),
+            1,
+        )
+        .expect("Can't build backend");
+        let (playback_tx, playback_rx) = ring::ring(19200);
+        let (reference_tx, reference_rx) = ring::ring(9600);
+        // What the ServCon has to play, well over the 200 ms the input lasts
+        playback_tx.write(&[0.5; 19200]);
+        let playback = Playback::new(
+            backend.output_format().unwrap(),
+            1,
+            playback_rx,
+            reference_tx,
+            Quality::High,
+        );
+
+        let options = ControlOptions {
+            echo_cancel: false,
+            ..Default::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        let capture = Capture::new(
+            input_format,
+            1,
+            tx,
+            reference_rx,
+            Quality::High,
+            Controls::new(&options),
+        );
+        let (_shutdown_tx, shutdown_rx) = mpsc::channel();
+        Box::new(backend)
+            .run(capture, playback, shutdown_rx)
+            .expect("Can't run backend");
+
+        let mut captured = 0;
+        let mut finished = false;
+        for msg in rx.try_iter() {
+            match msg {
+                MicMsg::AudioFromMic(audio) => captured += audio.len(),
+                MicMsg::Shutdown => finished = true,
+                MicMsg::Command(_) => {}
+            }
+        }
+        assert!(finished);
+        assert!(captured.abs_diff(9600) < 480, "captured {}", captured);
+
+        let (output_format, played) = wav::read(&output_path).expect("Can't read WAV");
+        fs::remove_file(&input_path).expect("Can't remove WAV");
+        fs::remove_file(&output_path).expect("Can't remove WAV");
+        assert_eq!(
+            output_format,
+            Format {
+                sample_rate: 48000,
+                channels: 1
+            }
+        );
+        assert_eq!(played.len(), 9600);
+        // Past the resampler's start-up the ring comes out as it went in
+        assert!(played[100..].iter().all(|smp| (smp - 0.5).abs() < 1e-3));
+    }
+}
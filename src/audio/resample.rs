@@ -0,0 +1,398 @@
+use std::f64::consts::PI;
+
+/// Trade-off between CPU use and fidelity of the sample rate conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quality {
+    /// Linear interpolation
+    Low,
+    /// Cubic Hermite interpolation
+    Medium,
+    /// Windowed sinc with anti-aliasing
+    #[default]
+    High,
+}
+
+impl std::str::FromStr for Quality {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Quality::Low),
+            "medium" => Ok(Quality::Medium),
+            "high" => Ok(Quality::High),
+            _ => anyhow::bail!(
+                "Unknown resampling quality {:?}, expected low, medium or high",
+                s
+            ),
+        }
+    }
+}
+
+/// Half-width of the sinc kernel, in input samples
+const SINC_HALF_WIDTH: usize = 16;
+/// Fractional positions the sinc kernel is tabulated at
+const SINC_PHASES: usize = 256;
+
+/// Streaming sample rate converter for a mono signal.
+///
+/// Output sample `k` corresponds to input time `k * ratio`, so a converted
+/// signal lines up with the original one without any extra delay to account for.
+pub struct Resampler {
+    quality: Quality,
+    /// Input samples per output sample
+    ratio: f64,
+    /// Ratio given by the nominal rates, before drift correction
+    nominal_ratio: f64,
+    /// Input samples not consumed yet, including the history the kernel looks back at
+    buf: Vec<f32>,
+    /// Position of the next output sample in `buf`, split so that dropping
+    /// consumed input doesn't disturb the fraction
+    index: usize,
+    frac: f64,
+    /// Samples the kernel needs on each side of the position
+    half_width: usize,
+    /// `SINC_PHASES + 1` rows of `2 * half_width` taps, only for `Quality::High`
+    sinc_table: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, quality: Quality) -> Self {
+        let ratio = from_rate as f64 / to_rate as f64;
+        let half_width = match quality {
+            Quality::Low => 1,
+            Quality::Medium => 2,
+            Quality::High => SINC_HALF_WIDTH,
+        };
+        let sinc_table = match quality {
+            Quality::High => sinc_table(half_width, ratio),
+            _ => vec![],
+        };
+        Self {
+            quality,
+            ratio,
+            nominal_ratio: ratio,
+            // Start with silent history so the first output lines up with the first input
+            buf: vec![0.0; half_width - 1],
+            index: half_width - 1,
+            frac: 0.0,
+            half_width,
+            sinc_table,
+        }
+    }
+
+    /// Scales the nominal ratio by `factor` to absorb clock drift, above 1 consuming
+    /// input faster. Meant for corrections of a fraction of a percent.
+    pub fn set_correction(&mut self, factor: f64) {
+        self.ratio = self.nominal_ratio * factor;
+    }
+
+    /// How many more input samples `process` needs to produce `out_len` samples
+    pub fn input_needed(&self, out_len: usize) -> usize {
+        if out_len == 0 {
+            return 0;
+        }
+        let last = self.index + (self.frac + (out_len - 1) as f64 * self.ratio).floor() as usize;
+        (last + self.half_width + 1).saturating_sub(self.buf.len())
+    }
+
+    /// Converts `input` and appends the result to `output`
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.buf.extend_from_slice(input);
+        while self.index + self.half_width < self.buf.len() {
+            output.push(self.interpolate());
+            self.advance();
+        }
+        self.consume();
+    }
+
+    /// Converts `input` into at most `out.len()` samples, returns how many were written.
+    /// Feed it `input_needed(out.len())` samples to fill `out` completely.
+    pub fn process_into(&mut self, input: &[f32], out: &mut [f32]) -> usize {
+        self.buf.extend_from_slice(input);
+        let mut written = 0;
+        while written < out.len() && self.index + self.half_width < self.buf.len() {
+            out[written] = self.interpolate();
+            self.advance();
+            written += 1;
+        }
+        self.consume();
+        written
+    }
+
+    fn advance(&mut self) {
+        self.frac += self.ratio;
+        let whole = self.frac.floor();
+        self.index += whole as usize;
+        self.frac -= whole;
+    }
+
+    /// Drops input which the kernel won't look at anymore
+    fn consume(&mut self) {
+        let keep_from = (self.index + 1).saturating_sub(self.half_width);
+        let keep_from = keep_from.min(self.buf.len());
+        self.buf.drain(..keep_from);
+        self.index -= keep_from;
+    }
+
+    fn interpolate(&self) -> f32 {
+        let index = self.index;
+        let frac = self.frac as f32;
+        match self.quality {
+            Quality::Low => {
+                let (a, b) = (self.buf[index], self.buf[index + 1]);
+                a + (b - a) * frac
+            }
+            Quality::Medium => {
+                let (y0, y1, y2, y3) = (
+                    self.buf[index - 1],
+                    self.buf[index],
+                    self.buf[index + 1],
+                    self.buf[index + 2],
+                );
+                let c1 = 0.5 * (y2 - y0);
+                let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+                ((c3 * frac + c2) * frac + c1) * frac + y1
+            }
+            Quality::High => {
+                let taps = 2 * self.half_width;
+                let phase = frac * SINC_PHASES as f32;
+                // Rounding to f32 may push the phase onto the last row
+                let row = (phase.floor() as usize).min(SINC_PHASES - 1);
+                let phase_frac = phase - row as f32;
+                let first = &self.sinc_table[row * taps..(row + 1) * taps];
+                let second = &self.sinc_table[(row + 1) * taps..(row + 2) * taps];
+                let input = &self.buf[index + 1 - self.half_width..=index + self.half_width];
+                input
+                    .iter()
+                    .zip(first.iter().zip(second))
+                    .map(|(smp, (a, b))| smp * (a + (b - a) * phase_frac))
+                    .sum()
+            }
+        }
+    }
+}
+
+/// Blackman-windowed sinc taps for every tabulated fractional position, with
+/// the cutoff lowered below the output Nyquist frequency when downsampling
+fn sinc_table(half_width: usize, ratio: f64) -> Vec<f32> {
+    let cutoff = (1.0 / ratio).min(1.0);
+    let taps = 2 * half_width;
+    let mut table = Vec::with_capacity((SINC_PHASES + 1) * taps);
+    for phase in 0..=SINC_PHASES {
+        let frac = phase as f64 / SINC_PHASES as f64;
+        let row: Vec<f64> = (0..taps)
+            .map(|tap| {
+                let x = tap as f64 - (half_width - 1) as f64 - frac;
+                let t = x / half_width as f64;
+                if t.abs() >= 1.0 {
+                    return 0.0;
+                }
+                let window = 0.42 + 0.5 * (PI * t).cos() + 0.08 * (2.0 * PI * t).cos();
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x * cutoff).sin() / (PI * x * cutoff)
+                };
+                sinc * window
+            })
+            .collect();
+        // Normalize so that DC passes through unchanged
+        let sum: f64 = row.iter().sum();
+        table.extend(row.iter().map(|tap| (tap / sum) as f32));
+    }
+    table
+}
+
+/// Converts interleaved audio, running a `Resampler` per channel
+pub struct MultiResampler {
+    channels: Vec<Resampler>,
+    /// Per-channel scratch for the input and output of each call
+    planes: Vec<(Vec<f32>, Vec<f32>)>,
+}
+
+impl MultiResampler {
+    pub fn new(from_rate: u32, to_rate: u32, quality: Quality, channels: usize) -> Self {
+        Self {
+            channels: (0..channels)
+                .map(|_| Resampler::new(from_rate, to_rate, quality))
+                .collect(),
+            planes: vec![(vec![], vec![]); channels],
+        }
+    }
+
+    pub fn set_correction(&mut self, factor: f64) {
+        for channel in &mut self.channels {
+            channel.set_correction(factor);
+        }
+    }
+
+    /// How many more input frames `process` needs to produce `out_frames` frames
+    pub fn input_needed(&self, out_frames: usize) -> usize {
+        self.channels[0].input_needed(out_frames)
+    }
+
+    /// Converts interleaved `input` and appends the interleaved result to `output`
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let count = self.channels.len();
+        for (index, (channel, (plane_in, plane_out))) in
+            self.channels.iter_mut().zip(&mut self.planes).enumerate()
+        {
+            plane_in.clear();
+            plane_in.extend(input.iter().skip(index).step_by(count));
+            plane_out.clear();
+            channel.process(plane_in, plane_out);
+        }
+        let frames = self.planes[0].1.len();
+        output.extend((0..frames * count).map(|n| self.planes[n % count].1[n / count]));
+    }
+
+    /// Converts interleaved `input` into at most `out.len()` interleaved samples,
+    /// returns how many frames were written
+    pub fn process_into(&mut self, input: &[f32], out: &mut [f32]) -> usize {
+        let count = self.channels.len();
+        let mut written = 0;
+        for (index, (channel, (plane_in, plane_out))) in
+            self.channels.iter_mut().zip(&mut self.planes).enumerate()
+        {
+            plane_in.clear();
+            plane_in.extend(input.iter().skip(index).step_by(count));
+            plane_out.resize(out.len() / count, 0.0);
+            written = channel.process_into(plane_in, plane_out);
+            for (smp, converted) in out
+                .iter_mut()
+                .skip(index)
+                .step_by(count)
+                .zip(&plane_out[..written])
+            {
+                *smp = *converted;
+            }
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FREQ: f64 = 1000.0;
+
+    fn sine(rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| (2.0 * PI * FREQ * n as f64 / rate as f64).sin() as f32 * 0.5)
+            .collect()
+    }
+
+    /// Largest deviation from an ideal sine at `rate`, skipping the start-up transient
+    fn max_error(output: &[f32], rate: u32) -> f32 {
+        let ideal = sine(rate, output.len());
+        output
+            .iter()
+            .zip(&ideal)
+            .skip(2 * SINC_HALF_WIDTH)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    fn convert(from: u32, to: u32, quality: Quality) -> Vec<f32> {
+        let mut resampler = Resampler::new(from, to, quality);
+        let mut output = vec![];
+        resampler.process(&sine(from, from as usize / 10), &mut output);
+        output
+    }
+
+    #[test]
+    fn upsamples_sine() {
+        for (quality, tolerance) in [
+            (Quality::Low, 5e-3),
+            (Quality::Medium, 1e-3),
+            (Quality::High, 1e-3),
+        ] {
+            let output = convert(44100, 48000, quality);
+            assert!(output.len().abs_diff(4800) <= 2 * SINC_HALF_WIDTH);
+            let error = max_error(&output, 48000);
+            assert!(error < tolerance, "{:?}: error {}", quality, error);
+        }
+    }
+
+    #[test]
+    fn downsamples_sine() {
+        for (quality, tolerance) in [
+            (Quality::Low, 5e-3),
+            (Quality::Medium, 1e-3),
+            (Quality::High, 1e-3),
+        ] {
+            let output = convert(48000, 44100, quality);
+            assert!(output.len().abs_diff(4410) <= 2 * SINC_HALF_WIDTH);
+            let error = max_error(&output, 44100);
+            assert!(error < tolerance, "{:?}: error {}", quality, error);
+        }
+    }
+
+    #[test]
+    fn same_rate_passes_through() {
+        let input = sine(48000, 4800);
+        for quality in [Quality::Low, Quality::Medium, Quality::High] {
+            let output = convert(48000, 48000, quality);
+            let len = output.len();
+            for (a, b) in output.iter().zip(&input[..len]) {
+                assert!((a - b).abs() < 1e-5, "{:?}", quality);
+            }
+        }
+    }
+
+    #[test]
+    fn correction_changes_rate() {
+        let input = sine(48000, 48000);
+        let mut resampler = Resampler::new(48000, 48000, Quality::High);
+        resampler.set_correction(1.005);
+        let mut output = vec![];
+        resampler.process(&input, &mut output);
+        let expected = (48000.0 / 1.005) as usize;
+        assert!(output.len().abs_diff(expected) <= 2 * SINC_HALF_WIDTH);
+    }
+
+    #[test]
+    fn chunked_matches_one_shot() {
+        let input = sine(44100, 4410);
+        let one_shot = convert(44100, 48000, Quality::High);
+
+        let mut resampler = Resampler::new(44100, 48000, Quality::High);
+        let mut chunked = vec![];
+        for chunk in input.chunks(441) {
+            resampler.process(chunk, &mut chunked);
+        }
+        assert_eq!(one_shot, chunked);
+    }
+
+    #[test]
+    fn input_needed_fills_output() {
+        let input = sine(44100, 44100);
+        let mut input = input.as_slice();
+        let mut resampler = Resampler::new(44100, 48000, Quality::High);
+        for _ in 0..20 {
+            let mut out = [0.0; 512];
+            let needed = resampler.input_needed(out.len());
+            let written = resampler.process_into(&input[..needed], &mut out);
+            input = &input[needed..];
+            assert_eq!(written, out.len());
+        }
+    }
+
+    #[test]
+    fn keeps_channels_apart() {
+        let left = sine(44100, 4410);
+        let stereo: Vec<f32> = left.iter().flat_map(|&smp| [smp, 0.0]).collect();
+        let mut resampler = MultiResampler::new(44100, 48000, Quality::High, 2);
+        let mut output = vec![];
+        resampler.process(&stereo, &mut output);
+
+        let mono = convert(44100, 48000, Quality::High);
+        assert_eq!(output.len(), 2 * mono.len());
+        for (frame, expected) in output.chunks(2).zip(&mono) {
+            assert_eq!(frame[0], *expected);
+            assert_eq!(frame[1], 0.0);
+        }
+    }
+}
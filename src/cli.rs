@@ -0,0 +1,163 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use discurse::protocol::valid_nickname;
+
+use crate::audio::AudioOptions;
+use crate::codec::CodecOptions;
+use crate::config;
+use crate::controls::ControlOptions;
+use crate::serv_con_emu::LoopbackOptions;
+
+const DEFAULT_ADDR: &str = "zezic.ru:13337";
+/// Longest loopback delay or jitter, ms
+const MAX_LOOPBACK_DELAY_MS: f32 = 10000.0;
+
+const USAGE: &str = "Usage: discurse [OPTIONS] [ADDR]
+
+Options:
+  --nickname <NAME>          nickname to join with
+  --config <PATH>            client config (default ~/.config/discurse/client.conf)
+  --list-devices             list audio hosts and devices, then exit
+  --host <NAME>              audio host, e.g. ALSA or JACK
+  --input-device <DEVICE>    microphone, by name or index
+  --output-device <DEVICE>   speakers, by name or index
+  --input <SOURCE>           wav:<PATH>, tone[:<HZ>], noise or silence instead of a microphone
+  --output <SINK>            wav:<PATH> or null instead of speakers
+  --resample-quality <Q>     low, medium or high (default high)
+  --gain <DB>                microphone gain (default 12)
+  --agc                      adjust the microphone gain automatically
+  --agc-target <DBFS>        level the AGC aims for (default -20)
+  --agc-attack <MS>          how fast the AGC turns down (default 10)
+  --agc-release <MS>         how fast the AGC turns back up (default 500)
+  --denoise                  suppress background noise from the microphone
+  --no-echo-cancel           don't cancel echo of the speakers, e.g. with headphones
+  --transmit <MODE>          open, vad or ptt (default vad)
+  --bitrate <BPS>            auto, max or bits per second (default auto)
+  --vbr <MODE>               on, off or constrained (default on)
+  --frame <MS>               2.5, 5, 10, 20, 40 or 60 (default 20)
+  --stereo                   capture, send and play two channels
+  --fec                      add in-band forward error correction
+  --loss <PCT>               packet loss the FEC is tuned for (default 0)
+  --dtx                      let Opus skip silent frames within speech
+  --complexity <N>           0 to 10, trading CPU for quality (default 10)
+  --application <APP>        voip, audio or lowdelay (default voip)
+  --loopback                 hear yourself through the codec instead of connecting
+  --loopback-delay <MS>      one-way delay of the loopback (default 0)
+  --loopback-jitter <MS>     random extra delay of up to this much (default 0)
+  --loopback-loss <PCT>      packets the loopback loses (default 0)
+  --help                     print this help";
+
+pub struct Args {
+    pub addr: String,
+    pub nickname: Option<String>,
+    pub config: Option<PathBuf>,
+    pub list_devices: bool,
+    pub audio: AudioOptions,
+    pub controls: ControlOptions,
+    /// Codec settings given on the command line, applied over the config
+    pub codec: Vec<(String, String)>,
+    /// Talk to a local loopback instead of the server at `addr`
+    pub loopback: Option<LoopbackOptions>,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self> {
+        let mut args = Args {
+            addr: String::from(DEFAULT_ADDR),
+            nickname: None,
+            config: config::default_path(),
+            list_devices: false,
+            audio: AudioOptions::default(),
+            controls: ControlOptions::default(),
+            codec: vec![],
+            loopback: None,
+        };
+        let mut iter = env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--nickname" => {
+                    let nickname = value(&mut iter, &arg)?;
+                    if !valid_nickname(&nickname) {
+                        bail!("Invalid nickname {:?}", nickname);
+                    }
+                    args.nickname = Some(nickname);
+                }
+                "--config" => args.config = Some(value(&mut iter, &arg)?.into()),
+                "--list-devices" => args.list_devices = true,
+                "--host" => args.audio.host = Some(value(&mut iter, &arg)?),
+                "--input-device" => args.audio.input_device = Some(value(&mut iter, &arg)?),
+                "--output-device" => args.audio.output_device = Some(value(&mut iter, &arg)?),
+                "--input" => args.audio.input = Some(value(&mut iter, &arg)?.parse()?),
+                "--output" => args.audio.output = Some(value(&mut iter, &arg)?.parse()?),
+                "--resample-quality" => {
+                    args.audio.resample_quality = value(&mut iter, &arg)?.parse()?
+                }
+                "--gain" => args.controls.gain.gain_db = number(&mut iter, &arg)?,
+                "--agc" => args.controls.gain.agc = true,
+                "--agc-target" => args.controls.gain.agc_target_db = number(&mut iter, &arg)?,
+                "--agc-attack" => args.controls.gain.agc_attack_ms = number(&mut iter, &arg)?,
+                "--agc-release" => args.controls.gain.agc_release_ms = number(&mut iter, &arg)?,
+                "--denoise" => args.controls.denoise = true,
+                "--no-echo-cancel" => args.controls.echo_cancel = false,
+                "--transmit" => args.controls.transmit = value(&mut iter, &arg)?.parse()?,
+                "--stereo" | "--fec" | "--dtx" => {
+                    args.codec.push((arg[2..].to_owned(), String::from("true")))
+                }
+                "--bitrate" | "--vbr" | "--frame" | "--loss" | "--complexity" | "--application" => {
+                    let value = value(&mut iter, &arg)?;
+                    CodecOptions::default().set(&arg[2..], &value)?;
+                    args.codec.push((arg[2..].to_owned(), value));
+                }
+                "--loopback" => {
+                    args.loopback.get_or_insert_with(Default::default);
+                }
+                "--loopback-delay" => {
+                    args.loopback.get_or_insert_with(Default::default).delay_ms =
+                        number_in(&mut iter, &arg, 0.0, MAX_LOOPBACK_DELAY_MS)?
+                }
+                "--loopback-jitter" => {
+                    args.loopback.get_or_insert_with(Default::default).jitter_ms =
+                        number_in(&mut iter, &arg, 0.0, MAX_LOOPBACK_DELAY_MS)?
+                }
+                "--loopback-loss" => {
+                    args.loopback.get_or_insert_with(Default::default).loss_percent =
+                        number_in(&mut iter, &arg, 0.0, 100.0)?
+                }
+                "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                _ if arg.starts_with("--") => bail!("Unknown option {}\n\n{}", arg, USAGE),
+                _ => args.addr = arg,
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn value(iter: &mut impl Iterator<Item = String>, name: &str) -> Result<String> {
+    iter.next()
+        .with_context(|| format!("{} expects a value", name))
+}
+
+fn number(iter: &mut impl Iterator<Item = String>, name: &str) -> Result<f32> {
+    let value = value(iter, name)?;
+    value
+        .parse()
+        .with_context(|| format!("{} expects a number, got {:?}", name, value))
+}
+
+fn number_in(
+    iter: &mut impl Iterator<Item = String>,
+    name: &str,
+    min: f32,
+    max: f32,
+) -> Result<f32> {
+    let number = number(iter, name)?;
+    if !(min..=max).contains(&number) {
+        bail!("{} expects {} to {}, got {}", name, min, max, number);
+    }
+    Ok(number)
+}
@@ -0,0 +1,166 @@
+use anyhow::{bail, Context, Result};
+use audiopus::{coder::Encoder, Channels, SampleRate};
+use discurse::protocol::{Application, Bitrate, EncoderSettings, Vbr, OPUS_FRAME_SIZES};
+
+/// Opus encoder setup and frame size we ask the server for
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecOptions {
+    /// Samples per channel at 48 kHz
+    pub frame_size: u32,
+    /// Ask for a two-channel session instead of mono
+    pub stereo: bool,
+    pub encoder: EncoderSettings,
+}
+
+impl Default for CodecOptions {
+    fn default() -> Self {
+        Self {
+            // 20 ms
+            frame_size: 960,
+            stereo: false,
+            encoder: EncoderSettings {
+                bitrate: Bitrate::Auto,
+                vbr: Vbr::On,
+                fec: false,
+                packet_loss: 0,
+                dtx: false,
+                complexity: 10,
+                application: Application::Voip,
+            },
+        }
+    }
+}
+
+/// Opus' name for a session's channel count
+pub fn opus_channels(channels: usize) -> Channels {
+    if channels == 2 {
+        Channels::Stereo
+    } else {
+        Channels::Mono
+    }
+}
+
+impl CodecOptions {
+    /// Changes the setting named `key`, as found in the config or on the command line
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let encoder = &mut self.encoder;
+        match key {
+            "bitrate" => {
+                encoder.bitrate = match value {
+                    "auto" => Bitrate::Auto,
+                    "max" => Bitrate::Max,
+                    _ => match value.parse() {
+                        Ok(bits) if (500..=512000).contains(&bits) => Bitrate::Bits(bits),
+                        _ => bail!(
+                            "Bad bitrate {:?}, expected auto, max or 500 to 512000 bits per second",
+                            value
+                        ),
+                    },
+                }
+            }
+            "vbr" => {
+                encoder.vbr = match value {
+                    "off" => Vbr::Off,
+                    "on" => Vbr::On,
+                    "constrained" => Vbr::Constrained,
+                    _ => bail!("Bad VBR mode {:?}, expected on, off or constrained", value),
+                }
+            }
+            "frame" => {
+                let size = value
+                    .parse::<f32>()
+                    .ok()
+                    .map(|ms| (ms * 48.0) as u32)
+                    .filter(|size| OPUS_FRAME_SIZES.contains(size));
+                self.frame_size = match size {
+                    Some(size) => size,
+                    None => bail!(
+                        "Bad frame duration {:?}, expected 2.5, 5, 10, 20, 40 or 60 ms",
+                        value
+                    ),
+                }
+            }
+            "stereo" => self.stereo = value.parse().context("Bad stereo")?,
+            "fec" => encoder.fec = value.parse().context("Bad fec")?,
+            "loss" => match value.trim_end_matches('%').parse() {
+                Ok(loss) if loss <= 100 => encoder.packet_loss = loss,
+                _ => bail!("Bad packet loss {:?}, expected 0 to 100 %", value),
+            },
+            "dtx" => encoder.dtx = value.parse().context("Bad dtx")?,
+            "complexity" => match value.parse() {
+                Ok(complexity) if complexity <= 10 => encoder.complexity = complexity,
+                _ => bail!("Bad complexity {:?}, expected 0 to 10", value),
+            },
+            "application" => {
+                encoder.application = match value {
+                    "voip" => Application::Voip,
+                    "audio" => Application::Audio,
+                    "lowdelay" => Application::LowDelay,
+                    _ => bail!(
+                        "Bad application {:?}, expected voip, audio or lowdelay",
+                        value
+                    ),
+                }
+            }
+            _ => bail!("Unknown codec setting {}", key),
+        }
+        Ok(())
+    }
+
+    /// Settings in the form `set` reads them, to be written back to the config
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let encoder = &self.encoder;
+        let bitrate = match encoder.bitrate {
+            Bitrate::Auto => String::from("auto"),
+            Bitrate::Max => String::from("max"),
+            Bitrate::Bits(bits) => bits.to_string(),
+        };
+        let vbr = match encoder.vbr {
+            Vbr::Off => "off",
+            Vbr::On => "on",
+            Vbr::Constrained => "constrained",
+        };
+        let application = match encoder.application {
+            Application::Voip => "voip",
+            Application::Audio => "audio",
+            Application::LowDelay => "lowdelay",
+        };
+        vec![
+            ("bitrate", bitrate),
+            ("vbr", vbr.to_owned()),
+            ("frame", (self.frame_size as f32 / 48.0).to_string()),
+            ("stereo", self.stereo.to_string()),
+            ("fec", encoder.fec.to_string()),
+            ("loss", encoder.packet_loss.to_string()),
+            ("dtx", encoder.dtx.to_string()),
+            ("complexity", encoder.complexity.to_string()),
+            ("application", application.to_owned()),
+        ]
+    }
+
+    /// Builds an Opus encoder set up as asked
+    pub fn encoder(&self, channels: Channels) -> Result<Encoder> {
+        let settings = &self.encoder;
+        let application = match settings.application {
+            Application::Voip => audiopus::Application::Voip,
+            Application::Audio => audiopus::Application::Audio,
+            Application::LowDelay => audiopus::Application::LowDelay,
+        };
+        let mut encoder = Encoder::new(SampleRate::Hz48000, channels, application)
+            .context("Can't build Opus encoder")?;
+        let bitrate = match settings.bitrate {
+            Bitrate::Auto => audiopus::Bitrate::Auto,
+            Bitrate::Max => audiopus::Bitrate::Max,
+            Bitrate::Bits(bits) => audiopus::Bitrate::BitsPerSecond(bits as i32),
+        };
+        encoder.set_bitrate(bitrate)?;
+        encoder.set_vbr(settings.vbr != Vbr::Off)?;
+        encoder.set_vbr_constraint(settings.vbr == Vbr::Constrained)?;
+        encoder.set_inband_fec(settings.fec)?;
+        encoder.set_packet_loss_perc(settings.packet_loss)?;
+        encoder
+            .set_encoder_ctl_request(audiopus::ffi::OPUS_SET_DTX_REQUEST, settings.dtx as i32)?;
+        encoder.set_complexity(settings.complexity)?;
+        Ok(encoder)
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+use crate::codec::CodecOptions;
+
+/// Loudest a participant can be turned up to, four times as loud as sent
+pub const MAX_VOLUME: f32 = 4.0;
+
+/// Volume as `UserSettings` takes it, `None` if `volume` isn't a number at all
+pub fn clamp_volume(volume: f32) -> Option<f32> {
+    volume.is_finite().then(|| volume.clamp(0.0, MAX_VOLUME))
+}
+
+/// How we treat a remote participant locally, remembered by nickname
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserSettings {
+    /// Playback gain, 1.0 leaves the speaker as is
+    pub volume: f32,
+    /// Don't play this speaker at all
+    pub muted: bool,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+/// Client settings persisted across sessions, stored in an INI-like file:
+///
+/// ```text
+/// [codec]
+/// bitrate = 32000
+/// frame = 20
+///
+/// [user alice]
+/// volume = 0.5
+/// muted = false
+/// ```
+///
+/// Nicknames in section names have `\`, `[`, `]` and line breaks escaped with
+/// a backslash. Saving rewrites the whole file, so comments don't survive it.
+#[derive(Default)]
+pub struct ClientConfig {
+    path: Option<PathBuf>,
+    /// Encoder settings, which the command line overrides
+    pub codec: CodecOptions,
+    pub users: BTreeMap<String, UserSettings>,
+}
+
+/// `$XDG_CONFIG_HOME/discurse/client.conf`, falling back to `~/.config`
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("discurse").join("client.conf"))
+}
+
+impl ClientConfig {
+    /// Reads the config at `path`, a missing file being an empty config
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let mut config = ClientConfig {
+            path,
+            ..Default::default()
+        };
+        let path = match config.path.clone() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(config),
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Can't read config {}", path.display()))?;
+        config
+            .parse(&text)
+            .with_context(|| format!("Can't parse config {}", path.display()))?;
+        Ok(config)
+    }
+
+    fn parse(&mut self, text: &str) -> Result<()> {
+        enum Section<'a> {
+            None,
+            Codec,
+            User(&'a mut UserSettings),
+        }
+        let mut section = Section::None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                section = match name.strip_prefix("user ") {
+                    Some(nickname) => Section::User(self.users.entry(unescape(nickname)).or_default()),
+                    None if name == "codec" => Section::Codec,
+                    None => bail!("Unknown section [{}] on line {}", name, number + 1),
+                };
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => bail!("Expected key = value on line {}", number + 1),
+            };
+            match &mut section {
+                Section::None => bail!("{} on line {} is outside of any section", key, number + 1),
+                Section::Codec => self
+                    .codec
+                    .set(key, value)
+                    .with_context(|| format!("On line {}", number + 1))?,
+                Section::User(user) => match key {
+                    "volume" => match value.parse().ok().and_then(clamp_volume) {
+                        Some(volume) => user.volume = volume,
+                        None => bail!("Bad volume {} on line {}", value, number + 1),
+                    },
+                    "muted" => user.muted = value.parse().context("Bad muted")?,
+                    _ => bail!("Unknown key {} on line {}", key, number + 1),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the config back where it was loaded from, if anywhere,
+    /// replacing the file
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Can't create {}", dir.display()))?;
+        }
+        fs::write(path, self.text())
+            .with_context(|| format!("Can't write config {}", path.display()))
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        if self.codec != CodecOptions::default() {
+            text += "[codec]\n";
+            for (key, value) in self.codec.entries() {
+                text += &format!("{} = {}\n", key, value);
+            }
+            text += "\n";
+        }
+        for (nickname, user) in &self.users {
+            text += &format!(
+                "[user {}]\nvolume = {}\nmuted = {}\n\n",
+                escape(nickname),
+                user.volume,
+                user.muted
+            );
+        }
+        text
+    }
+}
+
+/// Keeps a nickname from ending its section name or starting a new line
+fn escape(nickname: &str) -> String {
+    let mut escaped = String::new();
+    for chr in nickname.chars() {
+        match chr {
+            '\\' | '[' | ']' => escaped.extend(['\\', chr]),
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            _ => escaped.push(chr),
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> String {
+    let mut nickname = String::new();
+    let mut chars = escaped.chars();
+    while let Some(chr) = chars.next() {
+        if chr != '\\' {
+            nickname.push(chr);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => nickname.push('\n'),
+            Some('r') => nickname.push('\r'),
+            Some(chr) => nickname.push(chr),
+            None => nickname.push('\\'),
+        }
+    }
+    nickname
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nicknames_stay_inside_their_section() {
+        let mut config = ClientConfig::default();
+        let settings = UserSettings {
+            volume: 0.5,
+            muted: true,
+        };
+        for nickname in ["x\n[codec]\nbitrate = 1", "a]b\\c[", "tab\there\r"] {
+            config.users.insert(nickname.to_owned(), settings);
+        }
+
+        let mut loaded = ClientConfig::default();
+        loaded.parse(&config.text()).expect("Can't parse saved config");
+        assert_eq!(loaded.users, config.users);
+        assert_eq!(loaded.codec, CodecOptions::default());
+    }
+
+    #[test]
+    fn clamps_volumes() {
+        let mut config = ClientConfig::default();
+        config
+            .parse("[user quiet]\nvolume = -1\n[user loud]\nvolume = 10\n")
+            .expect("Can't parse config");
+        assert_eq!(config.users["quiet"].volume, 0.0);
+        assert_eq!(config.users["loud"].volume, MAX_VOLUME);
+        for volume in ["nan", "inf", "-inf"] {
+            let text = format!("[user odd]\nvolume = {}\n", volume);
+            assert!(ClientConfig::default().parse(&text).is_err());
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::io::BufRead;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use crate::{config::clamp_volume, controls::Controls, Command, MicMsg};
+
+/// Echo test delay when none is given, ms
+const DEFAULT_ECHO_DELAY_MS: u32 = 1000;
+
+const HELP: &str = "Commands:
+  /clients          show who is in the room and their round trips to the server
+  /stats            show our round trip to the server and the jitter buffer stats of every speaker
+  /nick <name>      change nickname
+  /rooms            list rooms
+  /create <room>    create a room and switch to it
+  /join <room>      switch to another room
+  /gain [dB]        show or set the microphone gain
+  /agc on|off       toggle automatic gain control
+  /agc <dBFS>       set the level the AGC aims for
+  /denoise on|off   toggle noise suppression
+  /aec on|off       toggle echo cancellation
+  /mode <mode>      transmit on open, vad or ptt
+  /talk on|off      hold or release the push-to-talk key
+  /mute             toggle sending
+  /mute <user>      toggle playback of someone, just for us
+  /vol <user> <%>   set someone's playback volume from 0 to 400, 100 is as is
+  /deafen           toggle playback and sending
+  /echo [ms]        hear ourselves back from the server instead of talking to the room
+  /echo off         stop the echo test";
+
+fn split(line: &str) -> (&str, &str) {
+    match line.trim().split_once(' ') {
+        Some((cmd, arg)) => (cmd, arg.trim()),
+        None => (line.trim(), ""),
+    }
+}
+
+fn parse(line: &str) -> Option<Command> {
+    let (cmd, text) = split(line);
+    let arg = || (!text.is_empty()).then(|| text.to_owned());
+    match cmd {
+        "/clients" => Some(Command::ShowClients),
+        "/stats" => Some(Command::ShowStats),
+        "/nick" => arg().map(Command::Nickname),
+        "/rooms" => Some(Command::ListRooms),
+        "/create" => arg().map(Command::CreateRoom),
+        "/join" => arg().map(Command::JoinRoom),
+        "/mute" => arg().map(Command::MuteUser),
+        "/echo" => match text {
+            "" => Some(Command::EchoTest(Some(DEFAULT_ECHO_DELAY_MS))),
+            "off" => Some(Command::EchoTest(None)),
+            ms => ms.parse().ok().map(|ms| Command::EchoTest(Some(ms))),
+        },
+        "/vol" => {
+            let (user, percent) = text.rsplit_once(' ')?;
+            let percent: f32 = percent.trim_end_matches('%').parse().ok()?;
+            // Below zero would flip the phase rather than turn it down
+            let volume = clamp_volume(percent / 100.0)?;
+            Some(Command::Volume(user.trim().to_owned(), volume))
+        }
+        _ => None,
+    }
+}
+
+/// Applies the commands which only touch local audio settings,
+/// returns false if `line` isn't one of them
+fn control(line: &str, controls: &Controls) -> bool {
+    let gain = &controls.gain;
+    match split(line) {
+        ("/gain", "") => {}
+        ("/gain", db) => match db.parse() {
+            Ok(db) => gain.set_gain_db(db),
+            Err(_) => return false,
+        },
+        ("/agc", "on") => gain.set_agc(true),
+        ("/agc", "off") => gain.set_agc(false),
+        ("/agc", target) => match target.parse() {
+            Ok(target) => gain.set_agc_target_db(target),
+            Err(_) => return false,
+        },
+        ("/denoise", "on") => controls.set_denoise(true),
+        ("/denoise", "off") => controls.set_denoise(false),
+        ("/aec", "on") => controls.set_echo_cancel(true),
+        ("/aec", "off") => controls.set_echo_cancel(false),
+        ("/mode", mode) => match mode.parse() {
+            Ok(mode) => controls.set_transmit(mode),
+            Err(_) => return false,
+        },
+        ("/talk", "on") => controls.set_talk_key(true),
+        ("/talk", "off") => controls.set_talk_key(false),
+        ("/mute", "") => controls.set_muted(!controls.muted()),
+        ("/deafen", "") => controls.set_deafened(!controls.deafened()),
+        _ => return false,
+    }
+    let on_off = |on| if on { "on" } else { "off" };
+    println!(
+        "Gain {:.1} dB, AGC {}, applying {:.1} dB, noise suppression {}, echo cancellation {}",
+        gain.gain_db(),
+        on_off(gain.agc()),
+        gain.current_db(),
+        on_off(controls.denoise()),
+        on_off(controls.echo_cancel())
+    );
+    println!(
+        "Transmit {:?}, talk key {}, muted {}, deafened {}",
+        controls.transmit(),
+        on_off(controls.talk_key()),
+        on_off(controls.muted()),
+        on_off(controls.deafened())
+    );
+    true
+}
+
+/// Reads commands from stdin, applies local ones and forwards the rest to the ServCon
+pub fn console_reader(tx: Sender<MicMsg>, controls: Arc<Controls>) {
+    for line in std::io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() || control(&line, &controls) {
+            continue;
+        }
+        match parse(&line) {
+            Some(cmd) => {
+                if tx.send(MicMsg::Command(cmd)).is_err() {
+                    break;
+                }
+            }
+            None => println!("{}", HELP),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MAX_VOLUME;
+
+    fn volume(line: &str) -> Option<f32> {
+        match parse(line) {
+            Some(Command::Volume(user, volume)) if user == "bob" => Some(volume),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn clamps_volumes() {
+        assert_eq!(volume("/vol bob 50%"), Some(0.5));
+        assert_eq!(volume("/vol bob -50"), Some(0.0));
+        assert_eq!(volume("/vol bob 1000"), Some(MAX_VOLUME));
+        assert_eq!(volume("/vol bob inf"), None);
+        assert_eq!(volume("/vol bob nan"), None);
+    }
+}
@@ -0,0 +1,142 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use anyhow::bail;
+
+use crate::gain::{GainControl, GainOptions};
+
+/// When the microphone goes out to the room
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransmitMode {
+    /// All the time
+    Open,
+    /// While the voice activity detector hears speech
+    Vad,
+    /// While the talk key is held
+    PushToTalk,
+}
+
+impl FromStr for TransmitMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(TransmitMode::Open),
+            "vad" => Ok(TransmitMode::Vad),
+            "ptt" => Ok(TransmitMode::PushToTalk),
+            _ => bail!("Unknown transmit mode {:?}, expected open, vad or ptt", s),
+        }
+    }
+}
+
+/// Initial state of the controls, as given on the command line
+pub struct ControlOptions {
+    pub gain: GainOptions,
+    pub denoise: bool,
+    pub echo_cancel: bool,
+    pub transmit: TransmitMode,
+}
+
+impl Default for ControlOptions {
+    fn default() -> Self {
+        Self {
+            gain: GainOptions::default(),
+            denoise: false,
+            echo_cancel: true,
+            transmit: TransmitMode::Vad,
+        }
+    }
+}
+
+/// Audio settings shared between the console, the ServCon and the audio
+/// callbacks, which may be changed at any time without locking
+pub struct Controls {
+    pub gain: GainControl,
+    denoise: AtomicBool,
+    echo_cancel: AtomicBool,
+    transmit: AtomicU8,
+    /// Talk key is held, only matters for push-to-talk
+    talk_key: AtomicBool,
+    muted: AtomicBool,
+    deafened: AtomicBool,
+}
+
+impl Controls {
+    pub fn new(options: &ControlOptions) -> Arc<Self> {
+        Arc::new(Self {
+            gain: GainControl::new(&options.gain),
+            denoise: AtomicBool::new(options.denoise),
+            echo_cancel: AtomicBool::new(options.echo_cancel),
+            transmit: AtomicU8::new(options.transmit as u8),
+            talk_key: AtomicBool::new(false),
+            muted: AtomicBool::new(false),
+            deafened: AtomicBool::new(false),
+        })
+    }
+
+    pub fn denoise(&self) -> bool {
+        self.denoise.load(Ordering::Relaxed)
+    }
+
+    pub fn set_denoise(&self, denoise: bool) {
+        self.denoise.store(denoise, Ordering::Relaxed)
+    }
+
+    pub fn echo_cancel(&self) -> bool {
+        self.echo_cancel.load(Ordering::Relaxed)
+    }
+
+    pub fn set_echo_cancel(&self, echo_cancel: bool) {
+        self.echo_cancel.store(echo_cancel, Ordering::Relaxed)
+    }
+
+    pub fn transmit(&self) -> TransmitMode {
+        match self.transmit.load(Ordering::Relaxed) {
+            mode if mode == TransmitMode::Open as u8 => TransmitMode::Open,
+            mode if mode == TransmitMode::Vad as u8 => TransmitMode::Vad,
+            _ => TransmitMode::PushToTalk,
+        }
+    }
+
+    pub fn set_transmit(&self, mode: TransmitMode) {
+        self.transmit.store(mode as u8, Ordering::Relaxed)
+    }
+
+    pub fn talk_key(&self) -> bool {
+        self.talk_key.load(Ordering::Relaxed)
+    }
+
+    pub fn set_talk_key(&self, held: bool) {
+        self.talk_key.store(held, Ordering::Relaxed)
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed)
+    }
+
+    pub fn deafened(&self) -> bool {
+        self.deafened.load(Ordering::Relaxed)
+    }
+
+    /// Deafening stops both playback and sending
+    pub fn set_deafened(&self, deafened: bool) {
+        self.deafened.store(deafened, Ordering::Relaxed)
+    }
+
+    /// Whether the microphone should go out, given what the VAD hears
+    pub fn should_transmit(&self, speech: bool) -> bool {
+        if self.muted() || self.deafened() {
+            return false;
+        }
+        match self.transmit() {
+            TransmitMode::Open => true,
+            TransmitMode::Vad => speech,
+            TransmitMode::PushToTalk => self.talk_key(),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use crate::fft::Fft;
+
+/// Removes steady background noise from the microphone signal
+pub trait NoiseSuppressor: Send {
+    /// Processes mono 48 kHz audio in place. With `suppress` off the audio passes
+    /// through unchanged, but is still analysed so that toggling is seamless.
+    fn process(&mut self, buf: &mut [f32], suppress: bool);
+}
+
+/// Analysis frame, about 10.7 ms at 48 kHz
+const FFT_SIZE: usize = 512;
+const HOP: usize = FFT_SIZE / 2;
+const BINS: usize = FFT_SIZE / 2 + 1;
+/// Weight of the previous frame in the smoothed power the noise floor is tracked from
+const POWER_SMOOTHING: f32 = 0.8;
+/// Per-frame rise of the noise estimate, about 3 dB per second
+const NOISE_RISE: f32 = 1.004;
+/// Following the minimum puts the estimate below the mean noise power, this makes up for it
+const NOISE_BIAS: f32 = 2.0;
+/// Weight of the previous frame in the decision-directed a priori SNR
+const SNR_SMOOTHING: f32 = 0.98;
+/// Lowest gain applied to a bin, -20 dB
+const GAIN_FLOOR: f32 = 0.1;
+
+/// Short-time spectral denoiser: tracks the noise floor of every frequency bin
+/// by following its minimum and applies a Wiener gain on top of it
+pub struct SpectralDenoiser {
+    fft: Fft,
+    window: Vec<f32>,
+    /// Last `FFT_SIZE` input samples
+    input: VecDeque<f32>,
+    /// Input samples received since the last frame
+    pending: usize,
+    /// Overlap-add accumulator of the frame being synthesized
+    overlap: Vec<f32>,
+    output: VecDeque<f32>,
+    power: Vec<f32>,
+    noise: Vec<f32>,
+    /// Gain and posterior SNR of the previous frame per bin
+    gain: Vec<f32>,
+    snr: Vec<f32>,
+    started: bool,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl SpectralDenoiser {
+    pub fn new() -> Self {
+        Self {
+            fft: Fft::new(FFT_SIZE),
+            // Square root of a periodic Hann window, used on both analysis and
+            // synthesis, sums to one at 50 % overlap
+            window: (0..FFT_SIZE)
+                .map(|n| (0.5 - 0.5 * (2.0 * PI * n as f32 / FFT_SIZE as f32).cos()).sqrt())
+                .collect(),
+            input: vec![0.0; FFT_SIZE].into(),
+            pending: 0,
+            overlap: vec![0.0; FFT_SIZE],
+            // Primed so that there is always a full buffer to hand out
+            output: vec![0.0; HOP].into(),
+            power: vec![0.0; BINS],
+            noise: vec![0.0; BINS],
+            gain: vec![1.0; BINS],
+            snr: vec![1.0; BINS],
+            started: false,
+            re: vec![0.0; FFT_SIZE],
+            im: vec![0.0; FFT_SIZE],
+        }
+    }
+
+    fn frame(&mut self, suppress: bool) {
+        for (n, smp) in self.input.iter().enumerate() {
+            self.re[n] = smp * self.window[n];
+            self.im[n] = 0.0;
+        }
+        self.fft.forward(&mut self.re, &mut self.im);
+
+        for k in 0..BINS {
+            let power = self.re[k] * self.re[k] + self.im[k] * self.im[k];
+            if !self.started {
+                self.power[k] = power;
+                self.noise[k] = power;
+            }
+            self.power[k] = POWER_SMOOTHING * self.power[k] + (1.0 - POWER_SMOOTHING) * power;
+            self.noise[k] = self.power[k].min(self.noise[k] * NOISE_RISE).max(1e-12);
+
+            let snr = power / (self.noise[k] * NOISE_BIAS);
+            let prior = SNR_SMOOTHING * self.gain[k] * self.gain[k] * self.snr[k]
+                + (1.0 - SNR_SMOOTHING) * (snr - 1.0).max(0.0);
+            self.gain[k] = (prior / (1.0 + prior)).max(GAIN_FLOOR);
+            self.snr[k] = snr;
+
+            if suppress {
+                self.re[k] *= self.gain[k];
+                self.im[k] *= self.gain[k];
+                if k != 0 && k != FFT_SIZE / 2 {
+                    self.re[FFT_SIZE - k] *= self.gain[k];
+                    self.im[FFT_SIZE - k] *= self.gain[k];
+                }
+            }
+        }
+        self.started = true;
+
+        self.fft.inverse(&mut self.re, &mut self.im);
+        for n in 0..FFT_SIZE {
+            self.overlap[n] += self.re[n] * self.window[n];
+        }
+        self.output.extend(&self.overlap[..HOP]);
+        self.overlap.copy_within(HOP.., 0);
+        for smp in &mut self.overlap[HOP..] {
+            *smp = 0.0;
+        }
+    }
+}
+
+impl NoiseSuppressor for SpectralDenoiser {
+    fn process(&mut self, buf: &mut [f32], suppress: bool) {
+        for smp in buf.iter_mut() {
+            self.input.pop_front();
+            self.input.push_back(*smp);
+            self.pending += 1;
+            if self.pending == HOP {
+                self.pending = 0;
+                self.frame(suppress);
+            }
+            *smp = self.output.pop_front().unwrap_or(0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::rng::Rng;
+    use crate::wav::{self, Format};
+
+    const RATE: u32 = 48000;
+
+    /// Two seconds of a voice-like harmonic tone switching on and off every
+    /// half second, buried in white noise at about -35 dBFS
+    fn noisy_speech() -> Vec<f32> {
+        let mut rng = Rng::new(0x1234_5678);
+        (0..2 * RATE as usize)
+            .map(|n| {
+                let noise = rng.signed() * 0.03;
+                let talking = (n / (RATE as usize / 2)) % 2 == 1;
+                let t = n as f32 / RATE as f32;
+                let voice = if talking {
+                    (1..6)
+                        .map(|h| (2.0 * PI * 180.0 * h as f32 * t).sin() * 0.2 / h as f32)
+                        .sum()
+                } else {
+                    0.0
+                };
+                voice + noise
+            })
+            .collect()
+    }
+
+    fn rms_db(buf: &[f32]) -> f32 {
+        let power = buf.iter().map(|smp| smp * smp).sum::<f32>() / buf.len() as f32;
+        10.0 * power.log10()
+    }
+
+    #[test]
+    fn lowers_noise_floor() {
+        let path = std::env::temp_dir().join(format!("discurse-noisy-{}.wav", std::process::id()));
+        let format = Format {
+            sample_rate: RATE,
+            channels: 1,
+        };
+        wav::write(&path, format, &noisy_speech()).expect("Can't write WAV");
+        let (read_format, input) = wav::read(&path).expect("Can't read WAV");
+        assert_eq!(read_format, format);
+        fs::remove_file(&path).expect("Can't remove WAV");
+
+        let mut output = input.clone();
+        let mut denoiser = SpectralDenoiser::new();
+        for chunk in output.chunks_mut(480) {
+            denoiser.process(chunk, true);
+        }
+
+        // Compare the second pause, long after the noise estimate settled,
+        // and the voiced half second after it, shifted by the added latency
+        let pause = RATE as usize + FFT_SIZE..RATE as usize * 3 / 2;
+        let voiced = RATE as usize * 3 / 2 + FFT_SIZE..2 * RATE as usize;
+        let shifted = |range: std::ops::Range<usize>| range.start - FFT_SIZE..range.end - FFT_SIZE;
+
+        let noise_before = rms_db(&input[shifted(pause.clone())]);
+        let noise_after = rms_db(&output[pause]);
+        assert!(
+            noise_after < noise_before - 10.0,
+            "noise floor {:.1} dB -> {:.1} dB",
+            noise_before,
+            noise_after
+        );
+
+        let voice_before = rms_db(&input[shifted(voiced.clone())]);
+        let voice_after = rms_db(&output[voiced]);
+        assert!(
+            voice_after > voice_before - 3.0,
+            "voice {:.1} dB -> {:.1} dB",
+            voice_before,
+            voice_after
+        );
+    }
+
+    #[test]
+    fn passes_through_when_off() {
+        let input = noisy_speech();
+        let mut output = input.clone();
+        let mut denoiser = SpectralDenoiser::new();
+        for chunk in output.chunks_mut(480) {
+            denoiser.process(chunk, false);
+        }
+        for (a, b) in output[FFT_SIZE..].iter().zip(&input) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+/// Ratio correction per sample of fill error, so 10 ms of excess at 48 kHz asks for about 0.5 %
+const DRIFT_GAIN: f64 = 1e-5;
+/// Largest correction applied, small enough not to be heard as a pitch shift
+const MAX_CORRECTION: f64 = 0.005;
+
+/// Estimates how far a buffer between two clocks drifts away from its target
+/// fill and turns that into a factor for the resampling ratio which pulls it back.
+pub struct DriftCompensator {
+    /// Weight of each new measurement in the smoothed error
+    smoothing: f64,
+    /// Smoothed fill error, in samples
+    error: Option<f64>,
+}
+
+impl DriftCompensator {
+    pub fn new(smoothing: f64) -> Self {
+        Self {
+            smoothing,
+            error: None,
+        }
+    }
+
+    /// Takes the measured and the desired fill in samples, returns the factor to
+    /// scale the resampling ratio by. Above 1 the buffer gets drained faster.
+    pub fn update(&mut self, fill: usize, target: usize) -> f64 {
+        let error = fill as f64 - target as f64;
+        let smoothed = self.error.get_or_insert(error);
+        *smoothed += (error - *smoothed) * self.smoothing;
+        1.0 + (*smoothed * DRIFT_GAIN).clamp(-MAX_CORRECTION, MAX_CORRECTION)
+    }
+
+    /// Current correction in parts per million
+    pub fn ppm(&self) -> f32 {
+        let correction = self.error.unwrap_or(0.0) * DRIFT_GAIN;
+        (correction.clamp(-MAX_CORRECTION, MAX_CORRECTION) * 1e6) as f32
+    }
+}
@@ -0,0 +1,72 @@
+use std::f32::consts::PI;
+
+/// In-place radix-2 complex FFT with precomputed twiddle factors
+pub struct Fft {
+    size: usize,
+    /// `(cos, sin)` of `-2πk / size` for the first half of the circle
+    twiddles: Vec<(f32, f32)>,
+}
+
+impl Fft {
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+        Self {
+            size,
+            twiddles: (0..size / 2)
+                .map(|k| {
+                    let (sin, cos) = (-2.0 * PI * k as f32 / size as f32).sin_cos();
+                    (cos, sin)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn forward(&self, re: &mut [f32], im: &mut [f32]) {
+        self.transform(re, im, false);
+    }
+
+    /// Inverse transform, scaled by 1/N
+    pub fn inverse(&self, re: &mut [f32], im: &mut [f32]) {
+        self.transform(re, im, true);
+        for (re, im) in re.iter_mut().zip(im.iter_mut()) {
+            *re /= self.size as f32;
+            *im /= self.size as f32;
+        }
+    }
+
+    fn transform(&self, re: &mut [f32], im: &mut [f32], inverse: bool) {
+        let n = self.size;
+        let mut j = 0;
+        for i in 1..n {
+            let mut bit = n >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= n {
+            let stride = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (w_re, w_im) = self.twiddles[k * stride];
+                    let w_im = if inverse { -w_im } else { w_im };
+                    let (a, b) = (start + k, start + k + len / 2);
+                    let t_re = re[b] * w_re - im[b] * w_im;
+                    let t_im = re[b] * w_im + im[b] * w_re;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+            }
+            len <<= 1;
+        }
+    }
+}
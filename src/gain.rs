@@ -0,0 +1,181 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::{controls::Controls, mixer::Limiter};
+
+/// Rate the capture path runs at once resampled
+const SAMPLE_RATE: f32 = 48000.0;
+/// Time constant of the level detector
+const DETECTOR_MS: f32 = 50.0;
+/// Below this level the signal is taken for background noise and the AGC holds its gain
+const AGC_GATE_DBFS: f32 = -55.0;
+/// Range the AGC may move its own gain in
+const AGC_MIN_DB: f32 = -20.0;
+const AGC_MAX_DB: f32 = 30.0;
+
+/// Initial settings of the capture gain, as given on the command line
+pub struct GainOptions {
+    /// Fixed gain applied to the microphone, dB
+    pub gain_db: f32,
+    pub agc: bool,
+    /// RMS level the AGC aims for, dBFS
+    pub agc_target_db: f32,
+    /// How fast the AGC turns the gain down, ms
+    pub agc_attack_ms: f32,
+    /// How fast the AGC turns the gain back up, ms
+    pub agc_release_ms: f32,
+}
+
+impl Default for GainOptions {
+    fn default() -> Self {
+        Self {
+            // The ×4 the capture path always had
+            gain_db: 12.0,
+            agc: false,
+            agc_target_db: -20.0,
+            agc_attack_ms: 10.0,
+            agc_release_ms: 500.0,
+        }
+    }
+}
+
+/// f32 which can be shared with the audio callback without locking
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed)
+    }
+}
+
+/// Capture gain settings which may be changed while the audio is running,
+/// along with the gain currently applied for a UI to show
+pub struct GainControl {
+    gain_db: AtomicF32,
+    agc: AtomicBool,
+    agc_target_db: AtomicF32,
+    agc_attack_ms: AtomicF32,
+    agc_release_ms: AtomicF32,
+    /// Fixed and automatic gain together, as last applied
+    current_db: AtomicF32,
+}
+
+impl GainControl {
+    pub fn new(options: &GainOptions) -> Self {
+        Self {
+            gain_db: AtomicF32::new(options.gain_db),
+            agc: AtomicBool::new(options.agc),
+            agc_target_db: AtomicF32::new(options.agc_target_db),
+            agc_attack_ms: AtomicF32::new(options.agc_attack_ms),
+            agc_release_ms: AtomicF32::new(options.agc_release_ms),
+            current_db: AtomicF32::new(options.gain_db),
+        }
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.gain_db.load()
+    }
+
+    pub fn set_gain_db(&self, gain_db: f32) {
+        self.gain_db.store(gain_db)
+    }
+
+    pub fn agc(&self) -> bool {
+        self.agc.load(Ordering::Relaxed)
+    }
+
+    pub fn set_agc(&self, agc: bool) {
+        self.agc.store(agc, Ordering::Relaxed)
+    }
+
+    pub fn set_agc_target_db(&self, target_db: f32) {
+        self.agc_target_db.store(target_db)
+    }
+
+    /// Total gain applied to the microphone right now, dB
+    pub fn current_db(&self) -> f32 {
+        self.current_db.load()
+    }
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn linear_to_db(linear: f32) -> f32 {
+    20.0 * linear.max(1e-10).log10()
+}
+
+/// One-pole smoothing coefficient for a time constant in ms
+fn coefficient(ms: f32) -> f32 {
+    (-1.0 / (ms.max(0.1) / 1000.0 * SAMPLE_RATE)).exp()
+}
+
+/// Applies the fixed gain, then the AGC if enabled, then keeps the result from clipping
+pub struct InputGain {
+    controls: Arc<Controls>,
+    channels: usize,
+    /// Smoothed signal power after the fixed gain
+    power: f32,
+    /// Gain chosen by the AGC, dB
+    agc_db: f32,
+    limiter: Limiter,
+}
+
+impl InputGain {
+    pub fn new(controls: Arc<Controls>, channels: usize) -> Self {
+        Self {
+            controls,
+            channels,
+            power: 0.0,
+            agc_db: 0.0,
+            limiter: Limiter::new(),
+        }
+    }
+
+    /// Processes interleaved 48 kHz audio in place, every channel getting the same gain
+    pub fn process(&mut self, buf: &mut [f32]) {
+        let control = &self.controls.gain;
+        let gain = db_to_linear(control.gain_db());
+        let agc = control.agc();
+        let target_db = control.agc_target_db.load();
+        let detector = coefficient(DETECTOR_MS);
+        let attack = coefficient(control.agc_attack_ms.load());
+        let release = coefficient(control.agc_release_ms.load());
+
+        for frame in buf.chunks_mut(self.channels) {
+            frame.iter_mut().for_each(|smp| *smp *= gain);
+            if !agc {
+                continue;
+            }
+            let power = frame.iter().map(|smp| smp * smp).sum::<f32>() / frame.len() as f32;
+            self.power = detector * self.power + (1.0 - detector) * power;
+            let level_db = 10.0 * self.power.max(1e-20).log10();
+            if level_db > AGC_GATE_DBFS {
+                let wanted_db = (target_db - level_db).clamp(AGC_MIN_DB, AGC_MAX_DB);
+                let coef = if wanted_db < self.agc_db {
+                    attack
+                } else {
+                    release
+                };
+                self.agc_db = coef * self.agc_db + (1.0 - coef) * wanted_db;
+            }
+            let agc_gain = db_to_linear(self.agc_db);
+            frame.iter_mut().for_each(|smp| *smp *= agc_gain);
+        }
+        if !agc {
+            self.agc_db = 0.0;
+        }
+
+        self.limiter.process(buf);
+        control.current_db.store(linear_to_db(gain) + self.agc_db);
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::Instant;
+
+use audiopus::{coder::Decoder, SampleRate};
+use discurse::protocol::AudioFrame;
+use log::warn;
+
+use crate::audio::resample::{MultiResampler, Quality};
+use crate::codec::opus_channels;
+use crate::drift::DriftCompensator;
+
+/// Largest frame a peer may send us, 120 ms at 48 kHz, per channel
+const MAX_FRAME_SIZE: usize = 5760;
+const SAMPLES_PER_MS: f32 = 48.0;
+
+/// Upper bound of the target delay, 400 ms
+const MAX_TARGET_DELAY: usize = 19200;
+/// How many frames in a row are concealed before assuming the speaker went quiet
+const MAX_CONCEALED_IN_ROW: u32 = 3;
+/// How far above the target delay the buffer may grow before frames are skipped
+const OVERFLOW_FRAMES: usize = 2;
+/// Weight of each mix call's fill in the drift estimate, settling over a second or two
+const SPEAKER_DRIFT_SMOOTHING: f64 = 0.01;
+/// Drift correction stays within a fraction of a percent, where cubic interpolation is plenty
+const DRIFT_QUALITY: Quality = Quality::Medium;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JitterStats {
+    /// Audio currently buffered, ms
+    pub delay_ms: f32,
+    /// Delay the buffer is aiming for, ms
+    pub target_ms: f32,
+    /// Smoothed interarrival jitter, ms
+    pub jitter_ms: f32,
+    /// Frames filled in by Opus packet loss concealment
+    pub concealed: u64,
+    /// Frames recovered from the in-band FEC of the following frame
+    pub recovered: u64,
+    /// Frames which arrived after their playout time
+    pub late_dropped: u64,
+    /// Frames skipped to bring the delay back down to the target
+    pub overflow_dropped: u64,
+    /// Playout rate correction for the sender's clock drift, ppm
+    pub drift_ppm: f32,
+}
+
+impl fmt::Display for JitterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "delay {:.0}/{:.0} ms, jitter {:.1} ms, concealed {}, recovered {}, late {}, overflow {}, drift {:+.0} ppm",
+            self.delay_ms,
+            self.target_ms,
+            self.jitter_ms,
+            self.concealed,
+            self.recovered,
+            self.late_dropped,
+            self.overflow_dropped,
+            self.drift_ppm
+        )
+    }
+}
+
+/// Reorders one speaker's frames and releases them at a steady pace, adapting
+/// the delay to the measured network jitter and concealing lost frames.
+pub struct JitterBuffer {
+    decoder: Decoder,
+    /// Channels we decode to, whatever the speaker sends
+    channels: usize,
+    /// Frames waiting for playout, keyed by unwrapped sequence number
+    frames: BTreeMap<u64, AudioFrame>,
+    /// Decoded interleaved samples ready to be played
+    pcm: VecDeque<f32>,
+    /// Sequence number of the next frame to be played
+    next_seq: Option<u64>,
+    playing: bool,
+    /// Sender announced the end of its talk spurt, nothing to conceal once drained
+    talk_ended: bool,
+    concealed_in_row: u32,
+    frame_duration: usize,
+    /// Arrival time and timestamp of the previous frame, for jitter estimation
+    last_arrival: Option<(Instant, u64)>,
+    /// RFC 3550 interarrival jitter, in samples
+    jitter: f32,
+    /// Stretches or squeezes playout so that the sender's clock drift doesn't
+    /// slowly fill or starve the buffer
+    resampler: MultiResampler,
+    drift: DriftCompensator,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            decoder: Decoder::new(SampleRate::Hz48000, opus_channels(channels))
+                .expect("Can't build Opus decoder"),
+            channels,
+            frames: BTreeMap::new(),
+            pcm: VecDeque::new(),
+            next_seq: None,
+            playing: false,
+            talk_ended: false,
+            concealed_in_row: 0,
+            frame_duration: 0,
+            last_arrival: None,
+            jitter: 0.0,
+            resampler: MultiResampler::new(48000, 48000, DRIFT_QUALITY, channels),
+            drift: DriftCompensator::new(SPEAKER_DRIFT_SMOOTHING),
+            stats: JitterStats::default(),
+        }
+    }
+
+    /// Maps the wrapping 32-bit sequence number onto a monotonic one near `next_seq`
+    fn unwrap_seq(&self, seq: u32) -> u64 {
+        match self.next_seq {
+            Some(next) => {
+                let diff = seq.wrapping_sub(next as u32) as i32;
+                (next as i64 + diff as i64).max(0) as u64
+            }
+            None => seq as u64,
+        }
+    }
+
+    pub fn push(&mut self, frame: AudioFrame) {
+        let now = Instant::now();
+        if let Some((last_time, last_timestamp)) = self.last_arrival {
+            let arrival_diff = now.duration_since(last_time).as_secs_f32() * 1000.0 * SAMPLES_PER_MS;
+            let timestamp_diff = frame.timestamp as f32 - last_timestamp as f32;
+            let deviation = (arrival_diff - timestamp_diff).abs();
+            self.jitter += (deviation - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((now, frame.timestamp));
+        self.frame_duration = frame.duration as usize;
+        self.talk_ended = false;
+
+        let seq = self.unwrap_seq(frame.seq);
+        if self.playing && matches!(self.next_seq, Some(next) if seq < next) {
+            self.stats.late_dropped += 1;
+            return;
+        }
+        self.frames.insert(seq, frame);
+    }
+
+    /// Lets the buffer play out what it has and stop without concealing the silence after
+    pub fn end_of_talk(&mut self) {
+        self.talk_ended = true;
+    }
+
+    fn target_delay(&self) -> usize {
+        let target = self.frame_duration + (3.0 * self.jitter) as usize;
+        target.clamp(self.frame_duration, MAX_TARGET_DELAY)
+    }
+
+    fn buffered(&self) -> usize {
+        self.pcm.len() / self.channels + self.frames.len() * self.frame_duration
+    }
+
+    /// Decodes up to `len` samples per channel into `pcm`
+    fn decode(&mut self, packet: Option<&[u8]>, fec: bool, len: usize) -> bool {
+        let mut audio_output: Vec<f32> = vec![0.0; len * self.channels];
+        match self.decoder.decode_float(packet, &mut audio_output, fec) {
+            Ok(decoded_len) => {
+                self.pcm.extend(&audio_output[..decoded_len * self.channels]);
+                true
+            }
+            Err(err) => {
+                warn!("Can't decode: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Decodes the next frame into `pcm`, concealing it if it's missing.
+    /// Returns false once the speaker seems to have stopped.
+    fn decode_next(&mut self) -> bool {
+        let next = match self.next_seq {
+            Some(next) => next,
+            None => return false,
+        };
+
+        if self.buffered() > self.target_delay() + OVERFLOW_FRAMES * self.frame_duration {
+            if let Some((&seq, _)) = self.frames.iter().next() {
+                self.frames.remove(&seq);
+                self.next_seq = Some(seq + 1);
+                self.stats.overflow_dropped += 1;
+                return true;
+            }
+        }
+
+        if let Some(frame) = self.frames.remove(&next) {
+            self.next_seq = Some(next + 1);
+            self.concealed_in_row = 0;
+            self.decode(Some(&frame.data[..]), false, MAX_FRAME_SIZE);
+            return true;
+        }
+
+        if self.frames.is_empty()
+            && (self.talk_ended || self.concealed_in_row >= MAX_CONCEALED_IN_ROW)
+        {
+            return false;
+        }
+
+        self.next_seq = Some(next + 1);
+        self.concealed_in_row += 1;
+        let recovered = match self.frames.get(&(next + 1)) {
+            Some(following) => {
+                let data = following.data.clone();
+                self.decode(Some(&data), true, self.frame_duration)
+            }
+            None => false,
+        };
+        if recovered {
+            self.stats.recovered += 1;
+        } else {
+            self.decode(None, false, self.frame_duration);
+            self.stats.concealed += 1;
+        }
+        true
+    }
+
+    /// Adds up to `out.len()` interleaved samples of this speaker scaled by `gain` into `out`
+    pub fn mix_into(&mut self, out: &mut [f32], gain: f32) {
+        if !self.playing {
+            if self.frames.is_empty() || self.buffered() < self.target_delay() {
+                return;
+            }
+            self.playing = true;
+            self.concealed_in_row = 0;
+            self.next_seq = self.frames.keys().next().copied();
+            self.resampler = MultiResampler::new(48000, 48000, DRIFT_QUALITY, self.channels);
+        }
+
+        let correction = self.drift.update(self.buffered(), self.target_delay());
+        self.resampler.set_correction(correction);
+        let needed = self.resampler.input_needed(out.len() / self.channels) * self.channels;
+        while self.pcm.len() < needed {
+            if !self.decode_next() {
+                self.playing = false;
+                break;
+            }
+        }
+
+        let len = needed.min(self.pcm.len());
+        let input: Vec<f32> = self.pcm.drain(..len).collect();
+        let mut resampled = vec![0.0; out.len()];
+        let written = self.resampler.process_into(&input, &mut resampled) * self.channels;
+        for (smp, from_speaker) in out.iter_mut().zip(&resampled[..written]) {
+            *smp += from_speaker * gain;
+        }
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats {
+            delay_ms: self.buffered() as f32 / SAMPLES_PER_MS,
+            target_ms: self.target_delay() as f32 / SAMPLES_PER_MS,
+            jitter_ms: self.jitter / SAMPLES_PER_MS,
+            drift_ppm: self.drift.ppm(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecOptions;
+
+    const FRAME: usize = 960;
+
+    /// Encodes 20 ms frames of a tone under the given sequence numbers. The
+    /// timestamps stay put, so that frames pushed back to back don't count as jitter.
+    fn frames(seqs: &[u32]) -> Vec<AudioFrame> {
+        let encoder = CodecOptions::default()
+            .encoder(opus_channels(1))
+            .expect("Can't build Opus encoder");
+        let mut packet = vec![0; 4000];
+        seqs.iter()
+            .enumerate()
+            .map(|(n, &seq)| {
+                let tone: Vec<f32> = (n * FRAME..(n + 1) * FRAME)
+                    .map(|t| (t as f32 * 0.05).sin() * 0.25)
+                    .collect();
+                let len = encoder
+                    .encode_float(&tone, &mut packet)
+                    .expect("Can't encode");
+                AudioFrame {
+                    seq,
+                    timestamp: 0,
+                    duration: FRAME as u32,
+                    data: packet[..len].to_vec(),
+                }
+            })
+            .collect()
+    }
+
+    fn filled(seqs: &[u32]) -> JitterBuffer {
+        let mut buffer = JitterBuffer::new(1);
+        for frame in frames(seqs) {
+            buffer.push(frame);
+        }
+        buffer
+    }
+
+    #[test]
+    fn unwraps_seq() {
+        let mut buffer = JitterBuffer::new(1);
+        assert_eq!(buffer.unwrap_seq(7), 7);
+        buffer.next_seq = Some(u32::MAX as u64);
+        assert_eq!(buffer.unwrap_seq(0), u32::MAX as u64 + 1);
+        assert_eq!(buffer.unwrap_seq(u32::MAX - 1), u32::MAX as u64 - 1);
+
+        // Frames straddling the wrap play in order, none taken for late
+        let mut buffer = filled(&[u32::MAX - 1, u32::MAX]);
+        buffer.mix_into(&mut [0.0; FRAME / 2], 1.0);
+        for frame in frames(&[0, 1]) {
+            buffer.push(frame);
+        }
+        buffer.end_of_talk();
+        buffer.mix_into(&mut [0.0; 4 * FRAME], 1.0);
+        let stats = buffer.stats();
+        assert_eq!(stats.late_dropped, 0);
+        assert_eq!(stats.concealed, 0);
+        assert_eq!(stats.recovered, 0);
+    }
+
+    #[test]
+    fn drops_late_frames() {
+        let mut buffer = filled(&[0, 1, 2]);
+        buffer.mix_into(&mut [0.0; FRAME], 1.0);
+        for frame in frames(&[0]) {
+            buffer.push(frame);
+        }
+        let stats = buffer.stats();
+        assert_eq!(stats.late_dropped, 1);
+        assert_eq!(stats.overflow_dropped, 0);
+    }
+
+    #[test]
+    fn skips_frames_on_overflow() {
+        // The target is a frame, two more may pile up on top
+        let mut buffer = filled(&[0, 1, 2, 3, 4, 5]);
+        buffer.mix_into(&mut [0.0; FRAME], 1.0);
+        assert_eq!(buffer.stats().overflow_dropped, 3);
+    }
+
+    #[test]
+    fn recovers_with_fec_or_conceals() {
+        // 1 is concealed with nothing after it, 2 recovered from 3's FEC
+        let mut buffer = filled(&[0, 3]);
+        buffer.end_of_talk();
+        buffer.mix_into(&mut [0.0; 4 * FRAME], 1.0);
+        let stats = buffer.stats();
+        assert_eq!(stats.concealed, 1);
+        assert_eq!(stats.recovered, 1);
+        assert_eq!(stats.late_dropped, 0);
+    }
+}
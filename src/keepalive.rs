@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+/// How often each side pings the other
+pub const PING_INTERVAL: Duration = Duration::from_secs(2);
+/// Pings in a row going unanswered before the peer is taken for dead
+pub const MAX_MISSED_PINGS: u32 = 3;
+
+/// Weight of each new round trip in the smoothed one, as in TCP
+const RTT_SMOOTHING: f64 = 0.125;
+
+/// Pings a peer on schedule, tells a silent peer from a dead one and keeps a
+/// smoothed round trip time from the pongs
+pub struct Keepalive {
+    /// What ping timestamps count from
+    epoch: Instant,
+    next_ping: Instant,
+    /// Last ping has gone unanswered so far
+    awaiting: bool,
+    missed: u32,
+    rtt: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl Keepalive {
+    /// First ping goes out a ping interval after `now`
+    pub fn new(now: Instant) -> Self {
+        Self {
+            epoch: now,
+            next_ping: now + PING_INTERVAL,
+            awaiting: false,
+            missed: 0,
+            rtt: None,
+        }
+    }
+
+    pub fn next_ping(&self) -> Instant {
+        self.next_ping
+    }
+
+    /// Timestamp to ping with if a ping is due
+    pub fn ping(&mut self, now: Instant) -> Option<u64> {
+        if now < self.next_ping {
+            return None;
+        }
+        if self.awaiting {
+            self.missed += 1;
+        }
+        self.awaiting = true;
+        self.next_ping = now + PING_INTERVAL;
+        Some(now.duration_since(self.epoch).as_micros() as u64)
+    }
+
+    /// Takes the round trip of a ping the peer has answered
+    pub fn pong(&mut self, now: Instant, timestamp: u64) {
+        let sent = self.epoch + Duration::from_micros(timestamp);
+        let sample = now.saturating_duration_since(sent);
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+            None => sample,
+        });
+        self.awaiting = false;
+        self.missed = 0;
+    }
+
+    /// Peer has left too many pings unanswered
+    pub fn dead(&self) -> bool {
+        self.missed >= MAX_MISSED_PINGS
+    }
+
+    /// Smoothed round trip time, once a pong has come back
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smooths_rtt_and_counts_missed_pings() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(start);
+        assert_eq!(keepalive.ping(start), None);
+
+        let mut now = start + PING_INTERVAL;
+        let timestamp = keepalive.ping(now).expect("Ping is due");
+        keepalive.pong(now + Duration::from_millis(40), timestamp);
+        assert_eq!(keepalive.rtt(), Some(Duration::from_millis(40)));
+
+        now += PING_INTERVAL;
+        let timestamp = keepalive.ping(now).expect("Ping is due");
+        keepalive.pong(now + Duration::from_millis(120), timestamp);
+        assert_eq!(keepalive.rtt(), Some(Duration::from_millis(50)));
+
+        for _ in 0..=MAX_MISSED_PINGS {
+            assert!(!keepalive.dead());
+            now += PING_INTERVAL;
+            keepalive.ping(now).expect("Ping is due");
+        }
+        assert!(keepalive.dead());
+        keepalive.pong(now, timestamp);
+        assert!(!keepalive.dead());
+    }
+}
@@ -1 +1,2 @@
-pub mod protocol;
\ No newline at end of file
+pub mod keepalive;
+pub mod protocol;
@@ -1,57 +1,136 @@
-use std::env;
-use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread::JoinHandle;
 
 use anyhow::Result;
 use fast_log::Config;
-// use serv_con_emu::ServEmu;
+use log::info;
+use config::ClientConfig;
+use controls::Controls;
+use ring::{Consumer, Producer};
+use serv_con_emu::ServEmu;
 use serv_con_real::ServReal;
 
 mod audio;
+mod cli;
+mod codec;
+mod config;
+mod console;
+mod controls;
+mod denoise;
+mod drift;
+mod fft;
+mod gain;
+mod jitter;
+mod mixer;
+mod ring;
+mod rng;
 mod serv_con_emu;
 mod serv_con_real;
+mod transmit;
+mod vad;
+mod wav;
 
 pub enum MicMsg {
     AudioFromMic(Vec<f32>),
+    Command(Command),
     Shutdown,
 }
 
-pub enum SpeakerMsg {
-    AudioFromSrv(Vec<f32>),
+pub enum Command {
+    ShowClients,
+    ShowStats,
+    Nickname(String),
+    ListRooms,
+    CreateRoom(String),
+    JoinRoom(String),
+    /// Playback volume of a participant, by nickname or uuid
+    Volume(String, f32),
+    /// Toggle local mute of a participant, by nickname or uuid
+    MuteUser(String),
+    /// Have the server play our audio back after a delay in ms, `None` to stop
+    EchoTest(Option<u32>),
 }
 
+/// Playback buffer between the ServCon and the output callback, 200 ms at 48 kHz per channel
+const PLAYBACK_RING_SIZE: usize = 9600;
+
 pub trait ServCon {
-    fn run(self, tx: Sender<SpeakerMsg>, rx: Receiver<MicMsg>) -> JoinHandle<()>;
+    /// Channels capture and playback have to use
+    fn channels(&self) -> usize;
+    /// Runs until told to shut down, or until it fails for good
+    fn run(self, playback: Producer, rx: Receiver<MicMsg>) -> JoinHandle<Result<()>>;
+}
+
+/// Runs the ServCon with a playback ring to match its channels
+fn start(serv: impl ServCon, rx: Receiver<MicMsg>) -> (usize, Consumer, JoinHandle<Result<()>>) {
+    let channels = serv.channels();
+    let (playback_tx, playback_rx) = ring::ring(PLAYBACK_RING_SIZE * channels);
+    (channels, playback_rx, serv.run(playback_tx, rx))
+}
+
+/// Shuts the ServCon down once dropped. Whyever the audio stopped, panics
+/// included, the ServCon has nothing left to do.
+struct StopServCon(Sender<MicMsg>);
+
+impl Drop for StopServCon {
+    fn drop(&mut self) {
+        let _ = self.0.send(MicMsg::Shutdown);
+    }
 }
 
 fn main() -> Result<()> {
     fast_log::init(Config::new().console()).expect("Can't initialize logger");
 
-    let addr = env::args().into_iter().nth(1).unwrap_or(String::from("zezic.ru:13337"));
+    let args = cli::Args::parse()?;
+    if args.list_devices {
+        return audio::list_devices(&args.audio);
+    }
+
+    let config = ClientConfig::load(args.config)?;
+    let mut codec = config.codec.clone();
+    for (key, value) in &args.codec {
+        codec.set(key, value)?;
+    }
+    info!("Codec: {:?}", codec);
+    let controls = Controls::new(&args.controls);
+    let console_controls = controls.clone();
+    let serv_controls = controls.clone();
 
-    let (ctx, crx) = std::sync::mpsc::channel();
     let (stx, srx) = std::sync::mpsc::channel();
 
     let shutdown_stx = stx.clone();
+    let console_stx = stx.clone();
 
     let (shutdown_tx, shutdown_rx) = std::sync::mpsc::channel::<()>();
+    let audio_shutdown_tx = shutdown_tx.clone();
 
-    // let mut serv = ServEmu::new();
-    let mut serv = ServReal::new(addr);
-    let serv_handle = serv.run(ctx, srx);
+    let (channels, playback_rx, serv_handle) = match args.loopback {
+        Some(loopback) => start(ServEmu::new(codec, loopback, serv_controls), srx),
+        None => start(ServReal::new(args.addr, args.nickname, codec, serv_controls, config)?, srx),
+    };
 
+    let audio_stx = stx.clone();
     let audio_thread = std::thread::Builder::new()
         .name("Audio".into())
-        .spawn(move || audio::audio_worker(stx, crx, shutdown_rx))?;
+        .spawn(move || {
+            let _stop = StopServCon(audio_stx);
+            audio::audio_worker(stx, playback_rx, shutdown_rx, args.audio, controls, channels)
+        })?;
+
+    std::thread::Builder::new()
+        .name("Console".into())
+        .spawn(move || console::console_reader(console_stx, console_controls))?;
 
     ctrlc::set_handler(move || {
         shutdown_tx.send(()).expect("Can't send shutdown");
         shutdown_stx.send(MicMsg::Shutdown).expect("Can't send to serv emu");
     }).expect("Can't set Ctrl-C handler");
 
+    let served = serv_handle.join().expect("Can't join serv handle");
+    // Nothing is left to capture or play for, the audio may be gone already
+    let _ = audio_shutdown_tx.send(());
     audio_thread.join().expect("Can't join audio thread")?;
-    serv_handle.join().expect("Can't join serv handle");
+    served?;
 
     log::logger().flush();
     Ok(())
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use discurse::protocol::AudioFrame;
+use uuid::Uuid;
+
+use crate::config::UserSettings;
+use crate::jitter::{JitterBuffer, JitterStats};
+
+/// Level the limiter keeps the mix under
+const LIMITER_CEILING: f32 = 0.95;
+/// Per-sample recovery of the limiter gain, roughly 100 ms to recover from -6 dB at 48 kHz
+const LIMITER_RELEASE: f32 = 0.0001;
+
+/// Keeps a signal below the ceiling with an instant attack and slow release
+pub struct Limiter {
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self { gain: 1.0 }
+    }
+
+    pub fn process(&mut self, buf: &mut [f32]) {
+        for smp in buf {
+            let peak = smp.abs() * self.gain;
+            if peak > LIMITER_CEILING {
+                self.gain = LIMITER_CEILING / smp.abs();
+            }
+            *smp *= self.gain;
+            self.gain = (self.gain + LIMITER_RELEASE).min(1.0);
+        }
+    }
+}
+
+/// Decodes every remote speaker separately and sums them into one output
+pub struct Mixer {
+    channels: usize,
+    speakers: HashMap<Uuid, JitterBuffer>,
+    /// Local volume and mute of every participant we've been told about
+    users: HashMap<Uuid, UserSettings>,
+    limiter: Limiter,
+}
+
+impl Mixer {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            speakers: HashMap::new(),
+            users: HashMap::new(),
+            limiter: Limiter::new(),
+        }
+    }
+
+    pub fn push(&mut self, id: Uuid, frame: AudioFrame) {
+        let channels = self.channels;
+        self.speakers
+            .entry(id)
+            .or_insert_with(|| JitterBuffer::new(channels))
+            .push(frame);
+    }
+
+    pub fn end_of_talk(&mut self, id: &Uuid) {
+        if let Some(speaker) = self.speakers.get_mut(id) {
+            speaker.end_of_talk();
+        }
+    }
+
+    /// Settings have been given for this participant, even if only the defaults
+    pub fn has_user(&self, id: &Uuid) -> bool {
+        self.users.contains_key(id)
+    }
+
+    pub fn user(&self, id: &Uuid) -> UserSettings {
+        self.users.get(id).copied().unwrap_or_default()
+    }
+
+    pub fn set_user(&mut self, id: Uuid, settings: UserSettings) {
+        self.users.insert(id, settings);
+    }
+
+    /// Forgets a speaker which has left
+    pub fn remove(&mut self, id: &Uuid) {
+        self.speakers.remove(id);
+        self.users.remove(id);
+    }
+
+    /// Forgets every speaker, e.g. after switching rooms
+    pub fn clear(&mut self) {
+        self.speakers.clear();
+    }
+
+    /// Produces `len` interleaved samples of all active speakers mixed together
+    pub fn mix(&mut self, len: usize) -> Vec<f32> {
+        let mut out = vec![0.0; len];
+        for (id, speaker) in self.speakers.iter_mut() {
+            // Muted speakers are still drained so that unmuting picks up live audio
+            let user = self.users.get(id).copied().unwrap_or_default();
+            let gain = if user.muted { 0.0 } else { user.volume };
+            speaker.mix_into(&mut out, gain);
+        }
+        self.limiter.process(&mut out);
+        out
+    }
+
+    pub fn stats(&self) -> Vec<(Uuid, JitterStats)> {
+        self.speakers
+            .iter()
+            .map(|(&id, speaker)| (id, speaker.stats()))
+            .collect()
+    }
+}
@@ -1,12 +1,53 @@
 use std::{net::TcpStream, io::{Write, Read}, sync::mpsc::Sender};
 
+use anyhow::{bail, Context, Result};
+use borsh::schema::{BorshSchemaContainer, Definition};
 use borsh::{BorshSerialize, BorshDeserialize, BorshSchema};
 use log::warn;
 use uuid::Uuid;
 
 const INITIAL_RECV_BUF_SIZE: usize = 256;
 
-#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug)]
+/// Largest frame a peer may send. The size comes from the peer before the
+/// handshake, so it can't be trusted to allocate with
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+pub const PROTOCOL_VERSION: u64 = 10;
+
+/// Opens the preamble every peer sends before any message
+const PREAMBLE_MAGIC: [u8; 4] = *b"DSCR";
+
+/// Frame sizes Opus can encode, in samples per channel at 48 kHz (2.5 to 60 ms)
+pub const OPUS_FRAME_SIZES: [u32; 6] = [120, 240, 480, 960, 1920, 2880];
+
+/// Room every client lands in after connecting
+pub const LOBBY: &str = "lobby";
+
+/// Longest nickname the server accepts, in characters
+pub const MAX_NICKNAME_LEN: usize = 32;
+
+/// Longest room name the server accepts, in characters
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Nicknames are shown to everyone and stored in client configs, so they
+/// have to be short, printable and free of surrounding whitespace
+pub fn valid_nickname(nickname: &str) -> bool {
+    valid_name(nickname, MAX_NICKNAME_LEN)
+}
+
+/// Room names are shown to everyone too, and held to the same rules
+pub fn valid_room_name(room: &str) -> bool {
+    valid_name(room, MAX_ROOM_NAME_LEN)
+}
+
+fn valid_name(name: &str, max_len: usize) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= max_len
+        && name.trim() == name
+        && !name.chars().any(char::is_control)
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone, Copy)]
 pub struct UuidWrapper([u8; 16]);
 
 impl From<UuidWrapper> for Uuid {
@@ -21,39 +62,304 @@ impl From<Uuid> for UuidWrapper {
     }
 }
 
-#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug)]
-pub enum ClientMsg {
-    GetClients,
-    Nickname(String),
-    OpusAudio(Vec<u8>),
-    Leave,
+pub use messages::{Bitrate, ClientMsg, ServerMsg};
+
+/// Enums with data in their variants. The BorshSchema derive emits a helper
+/// struct per such variant which is never read, so dead code is allowed here only.
+#[allow(dead_code)]
+mod messages {
+    use super::*;
+
+    #[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug)]
+    pub enum ClientMsg {
+        Hello {
+            protocol_version: u64,
+            client_name: String,
+            client_version: String,
+            nickname: Option<String>,
+            capabilities: Capabilities,
+            encoder: EncoderSettings,
+            /// Token from an earlier Welcome, to pick up where that session left off
+            resume: Option<UuidWrapper>,
+        },
+        GetClients,
+        Nickname(String),
+        OpusAudio(AudioFrame),
+        Leave,
+        ListRooms,
+        CreateRoom(String),
+        JoinRoom(String),
+        /// Sender stopped talking, no audio follows until the next talk spurt
+        EndOfTalk,
+        /// Sender muted its microphone or deafened its speakers
+        Status { muted: bool, deafened: bool },
+        /// Asks the server to send our audio back to us alone after a delay in ms,
+        /// instead of to the room. `None` ends the echo test.
+        EchoTest(Option<u32>),
+        /// Asks for a `ServerMsg::Pong` with the same timestamp, in µs of the sender's clock
+        Ping(u64),
+        /// Answers a `ServerMsg::Ping`
+        Pong(u64),
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug)]
+    pub enum ServerMsg {
+        Welcome {
+            protocol_version: u64,
+            uuid: UuidWrapper,
+            params: SessionParams,
+            /// Lets the client resume this session if the connection drops
+            token: UuidWrapper,
+            /// The session from `ClientMsg::Hello::resume` was picked up, keeping
+            /// our uuid, nickname and room
+            resumed: bool,
+        },
+        Clients(Vec<ClientDescription>),
+        OpusAudio(UuidWrapper, AudioFrame),
+        Bye { reason: String },
+        ClientJoined(ClientDescription),
+        ClientLeft { uuid: UuidWrapper, reason: LeaveReason },
+        NicknameChanged { uuid: UuidWrapper, nickname: String },
+        Rooms(Vec<RoomDescription>),
+        RoomJoined(String),
+        RoomError(String),
+        EndOfTalk(UuidWrapper),
+        StatusChanged {
+            uuid: UuidWrapper,
+            muted: bool,
+            deafened: bool,
+        },
+        /// Echo test delay in ms the server went with, `None` once it's over
+        EchoTest(Option<u32>),
+        /// Client lost its connection, the server holds its place for a while
+        ClientReconnecting(UuidWrapper),
+        /// Client is back after losing its connection
+        ClientReconnected(UuidWrapper),
+        /// Asks for a `ClientMsg::Pong` with the same timestamp, in µs of the sender's clock
+        Ping(u64),
+        /// Answers a `ClientMsg::Ping`
+        Pong(u64),
+    }
+
+    #[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone, Copy)]
+    pub enum Bitrate {
+        /// Left to the encoder
+        Auto,
+        /// As much as fits into a packet
+        Max,
+        /// Bits per second
+        Bits(u32),
+    }
+}
+
+/// A single encoded frame, relayed by the server untouched
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone)]
+pub struct AudioFrame {
+    /// Per-sender frame counter, wraps around
+    pub seq: u32,
+    /// Sender's sample clock at the first sample of the frame, 48 kHz
+    pub timestamp: u64,
+    /// Frame duration in samples per channel at 48 kHz
+    pub duration: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone, Copy)]
+pub enum Codec {
+    Opus,
+}
+
+/// What the client is able to send and receive
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone)]
+pub struct Capabilities {
+    pub codecs: Vec<Codec>,
+    pub stereo: bool,
+    /// Frame sizes in samples per channel at 48 kHz, most preferred first
+    pub frame_sizes: Vec<u32>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone, Copy)]
+pub enum Vbr {
+    Off,
+    On,
+    /// Varies the bitrate but keeps it within the target over each frame
+    Constrained,
+}
+
+/// Opus application, what the encoder tunes itself for
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone, Copy)]
+pub enum Application {
+    Voip,
+    Audio,
+    LowDelay,
+}
+
+/// How the client has set up its encoder, reported to the server in Hello
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone)]
+pub struct EncoderSettings {
+    pub bitrate: Bitrate,
+    pub vbr: Vbr,
+    /// In-band forward error correction
+    pub fec: bool,
+    /// Packet loss the FEC is tuned for, %
+    pub packet_loss: u8,
+    /// Discontinuous transmission, skipping frames Opus deems silent
+    pub dtx: bool,
+    /// 0 to 10, trading CPU for quality
+    pub complexity: u8,
+    pub application: Application,
+}
+
+/// Parameters the server has chosen for the session
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone)]
+pub struct SessionParams {
+    pub codec: Codec,
+    pub channels: u8,
+    /// Frame size in samples per channel at 48 kHz
+    pub frame_size: u32,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug)]
-pub enum ServerMsg {
-    Version(u64),
-    Clients(Vec<ClientDescription>),
-    OpusAudio(UuidWrapper, Vec<u8>),
-    Bye { reason: String },
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone, Copy)]
+pub enum LeaveReason {
+    /// Client sent `ClientMsg::Leave`
+    Leave,
+    /// Connection to the client was lost
+    Disconnect,
+    /// Server dropped the client
+    Kicked,
+    /// Client switched to another room
+    Moved,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone)]
 pub struct ClientDescription {
-    nickname: Option<String>,
-    uuid: UuidWrapper,
+    pub nickname: Option<String>,
+    pub uuid: UuidWrapper,
+    /// Client isn't sending audio
+    pub muted: bool,
+    /// Client isn't listening, and isn't sending either
+    pub deafened: bool,
+    /// Smoothed round trip between the server and the client, once measured
+    pub rtt_ms: Option<u32>,
 }
 
-pub fn write_msg<T>(stream: &mut TcpStream, msg: T)
-where
-    T: BorshSerialize + BorshSchema,
-{
-    let bytes = borsh::try_to_vec_with_schema(&msg).expect("Can't serialize");
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, PartialEq, Debug, Clone)]
+pub struct RoomDescription {
+    pub name: String,
+    pub clients: u32,
+}
+
+fn write_frame(stream: &mut TcpStream, bytes: &[u8]) -> std::io::Result<()> {
     let size = bytes.len() as u32;
     let size_bytes = size.to_le_bytes();
+    stream.write_all(&size_bytes[0..4])?;
+    stream.write_all(bytes)
+}
+
+fn read_frame<'a>(stream: &mut TcpStream, buf: &'a mut Vec<u8>) -> Result<&'a [u8]> {
+    let mut size_buf = [0; 4];
+    stream
+        .read_exact(&mut size_buf)
+        .context("Can't read msg size from socket")?;
+    let pkt_size = u32::from_le_bytes(size_buf) as usize;
+    if pkt_size > MAX_FRAME_SIZE {
+        bail!("Msg of {} bytes is over the {} bytes limit", pkt_size, MAX_FRAME_SIZE);
+    }
+    if buf.len() < pkt_size {
+        buf.resize(pkt_size, 0);
+    }
     stream
-        .write_all(&size_bytes[0..4])
-        .expect("Can't write to stream");
-    stream.write_all(&bytes).expect("Can't write to stream");
+        .read_exact(&mut buf[0..pkt_size])
+        .context("Can't read msg body from socket")?;
+    Ok(&buf[0..pkt_size])
+}
+
+/// Sends our protocol version followed by the schema of the messages we are
+/// going to write. Has to go first, the messages themselves don't carry a schema.
+pub fn write_preamble<T>(stream: &mut TcpStream) -> Result<()>
+where
+    T: BorshSchema,
+{
+    let mut preamble = PREAMBLE_MAGIC.to_vec();
+    preamble.extend(PROTOCOL_VERSION.to_le_bytes());
+    write_frame(stream, &preamble).context("Can't write preamble")?;
+
+    let schema = T::schema_container()
+        .try_to_vec()
+        .expect("Can't serialize schema");
+    write_frame(stream, &schema).context("Can't write schema")
+}
+
+/// Reads the peer's preamble and checks that it speaks our version and writes
+/// messages we are able to parse
+pub fn read_preamble<M>(stream: &mut TcpStream) -> Result<()>
+where
+    M: BorshSchema,
+{
+    let mut buf = vec![];
+
+    let preamble = read_frame(stream, &mut buf)?;
+    if !preamble.starts_with(&PREAMBLE_MAGIC) {
+        match legacy_version(preamble) {
+            Some(version) => bail!(
+                "Peer speaks protocol version {}, we speak {}",
+                version,
+                PROTOCOL_VERSION
+            ),
+            None => bail!("Peer speaks an unknown protocol"),
+        }
+    }
+    let version = preamble[PREAMBLE_MAGIC.len()..]
+        .try_into()
+        .map(u64::from_le_bytes)
+        .context("Malformed preamble")?;
+    if version != PROTOCOL_VERSION {
+        bail!(
+            "Peer speaks protocol version {}, we speak {}",
+            version,
+            PROTOCOL_VERSION
+        );
+    }
+
+    let schema = read_frame(stream, &mut buf)?;
+    let schema = BorshSchemaContainer::try_from_slice(schema).context("Can't parse peer schema")?;
+    if schema != M::schema_container() {
+        bail!("Peer's {} schema differs from ours", schema.declaration);
+    }
+    Ok(())
+}
+
+/// Peers older than version 3 embed the schema into every frame and open with
+/// either `ServerMsg::Version(u64)` or `ClientMsg::Hello { protocol_version, .. }`,
+/// both of which start with the version right after the variant index.
+fn legacy_version(frame: &[u8]) -> Option<u64> {
+    let mut data = frame;
+    let schema = BorshSchemaContainer::deserialize(&mut data).ok()?;
+    let first_variant = match schema.definitions.get(&schema.declaration)? {
+        Definition::Enum { variants } => &variants.first()?.0,
+        _ => return None,
+    };
+    if (first_variant == "Version" || first_variant == "Hello") && data.first() == Some(&0) {
+        data.get(1..9)?.try_into().ok().map(u64::from_le_bytes)
+    } else {
+        None
+    }
+}
+
+pub fn write_msg<T>(stream: &mut TcpStream, msg: T)
+where
+    T: BorshSerialize,
+{
+    try_write_msg(stream, msg).expect("Can't write to stream");
+}
+
+/// Like `write_msg`, but leaves a broken connection to the caller
+pub fn try_write_msg<T>(stream: &mut TcpStream, msg: T) -> Result<()>
+where
+    T: BorshSerialize,
+{
+    let bytes = msg.try_to_vec().expect("Can't serialize");
+    write_frame(stream, &bytes).context("Can't write to stream")
 }
 
 pub trait FromMsg<M> {
@@ -64,31 +370,28 @@ pub trait Gone {
     fn gone(client_id: Option<Uuid>) -> Self;
 }
 
+/// Reads a single message, reusing `buf` for the body
+pub fn read_msg<M>(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<M>
+where
+    M: BorshDeserialize,
+{
+    let frame = read_frame(stream, buf)?;
+    let msg = M::try_from_slice(frame).context("Can't parse msg body from socket")?;
+    Ok(msg)
+}
+
 pub fn socket_reader<T, M>(mut stream: TcpStream, peer_id: Option<Uuid>, crtx: Sender<T>)
 where
     T: FromMsg<M> + Gone,
-    M: BorshDeserialize + BorshSchema,
+    M: BorshDeserialize,
 {
     let mut buf = vec![0; INITIAL_RECV_BUF_SIZE];
 
     loop {
-        let mut size_buf = [0; 4];
-        if let Err(err) = stream.read_exact(&mut size_buf) {
-            warn!("Can't read msg size from socket: {}", err);
-            break;
-        }
-        let pkt_size = u32::from_le_bytes(size_buf) as usize;
-        if buf.len() < pkt_size {
-            buf.resize(pkt_size, 0);
-        }
-        if let Err(err) = stream.read_exact(&mut buf[0..pkt_size]) {
-            warn!("Can't read msg body from socket: {}", err);
-            break;
-        }
-        let msg: M = match borsh::try_from_slice_with_schema(&buf[0..pkt_size]) {
+        let msg: M = match read_msg(&mut stream, &mut buf) {
             Ok(msg) => msg,
             Err(err) => {
-                warn!("Can't parse msg body from socket: {}", err);
+                warn!("{:#}", err);
                 break;
             }
         };
@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Samples shared between exactly one writer and one reader. Neither side ever
+/// blocks or allocates, so the reader may live in a realtime audio callback.
+struct Ring {
+    /// f32 samples stored as bits
+    buf: Box<[AtomicU32]>,
+    /// Total samples ever read
+    head: AtomicUsize,
+    /// Total samples ever written
+    tail: AtomicUsize,
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RingStats {
+    /// Samples currently buffered
+    pub fill: usize,
+    pub capacity: usize,
+    /// Times the reader wanted more than was buffered
+    pub underruns: u64,
+    /// Times the writer had to drop samples because the ring was full
+    pub overruns: u64,
+}
+
+pub struct Producer {
+    ring: Arc<Ring>,
+}
+
+pub struct Consumer {
+    ring: Arc<Ring>,
+}
+
+pub fn ring(capacity: usize) -> (Producer, Consumer) {
+    let ring = Arc::new(Ring {
+        buf: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        underruns: AtomicU64::new(0),
+        overruns: AtomicU64::new(0),
+    });
+    (Producer { ring: ring.clone() }, Consumer { ring })
+}
+
+impl Ring {
+    fn stats(&self) -> RingStats {
+        RingStats {
+            fill: self.tail.load(Ordering::Acquire) - self.head.load(Ordering::Acquire),
+            capacity: self.buf.len(),
+            underruns: self.underruns.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Producer {
+    /// Writes as much of `data` as fits, returns how much was written
+    pub fn write(&self, data: &[f32]) -> usize {
+        let ring = &self.ring;
+        let head = ring.head.load(Ordering::Acquire);
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let free = ring.buf.len() - (tail - head);
+        let len = data.len().min(free);
+        for (i, smp) in data[..len].iter().enumerate() {
+            ring.buf[(tail + i) % ring.buf.len()].store(smp.to_bits(), Ordering::Relaxed);
+        }
+        ring.tail.store(tail + len, Ordering::Release);
+        if len < data.len() {
+            ring.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        len
+    }
+
+    pub fn stats(&self) -> RingStats {
+        self.ring.stats()
+    }
+}
+
+impl Consumer {
+    /// Reads up to `out.len()` samples, returns how many were read
+    pub fn read(&self, out: &mut [f32]) -> usize {
+        let ring = &self.ring;
+        let tail = ring.tail.load(Ordering::Acquire);
+        let head = ring.head.load(Ordering::Relaxed);
+        let len = out.len().min(tail - head);
+        for (i, smp) in out[..len].iter_mut().enumerate() {
+            *smp = f32::from_bits(ring.buf[(head + i) % ring.buf.len()].load(Ordering::Relaxed));
+        }
+        ring.head.store(head + len, Ordering::Release);
+        if len < out.len() {
+            ring.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        len
+    }
+
+    /// Throws away up to `len` of the oldest samples
+    pub fn skip(&self, len: usize) {
+        let ring = &self.ring;
+        let tail = ring.tail.load(Ordering::Acquire);
+        let head = ring.head.load(Ordering::Relaxed);
+        ring.head.store(head + len.min(tail - head), Ordering::Release);
+    }
+
+    pub fn stats(&self) -> RingStats {
+        self.ring.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_around() {
+        let (producer, consumer) = ring(4);
+        let mut out = [0.0; 3];
+        for round in 0..5 {
+            let base = round as f32 * 3.0;
+            assert_eq!(producer.write(&[base, base + 1.0, base + 2.0]), 3);
+            assert_eq!(consumer.read(&mut out), 3);
+            assert_eq!(out, [base, base + 1.0, base + 2.0]);
+        }
+        let stats = consumer.stats();
+        assert_eq!((stats.fill, stats.underruns, stats.overruns), (0, 0, 0));
+    }
+
+    #[test]
+    fn writes_what_fits_on_overrun() {
+        let (producer, consumer) = ring(4);
+        assert_eq!(producer.write(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(producer.write(&[4.0, 5.0, 6.0]), 1);
+        let stats = producer.stats();
+        assert_eq!((stats.fill, stats.overruns), (4, 1));
+
+        let mut out = [0.0; 4];
+        assert_eq!(consumer.read(&mut out), 4);
+        assert_eq!(out, [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn counts_underruns() {
+        let (producer, consumer) = ring(4);
+        producer.write(&[1.0, 2.0]);
+        let mut out = [0.0; 3];
+        assert_eq!(consumer.read(&mut out), 2);
+        assert_eq!(&out[..2], [1.0, 2.0]);
+        assert_eq!(consumer.read(&mut out), 0);
+        assert_eq!(consumer.stats().underruns, 2);
+        // Reading nothing isn't an underrun
+        assert_eq!(consumer.read(&mut []), 0);
+        assert_eq!(consumer.stats().underruns, 2);
+    }
+
+    #[test]
+    fn skips_the_oldest() {
+        let (producer, consumer) = ring(4);
+        producer.write(&[1.0, 2.0, 3.0]);
+        consumer.skip(2);
+        producer.write(&[4.0]);
+        consumer.skip(1);
+        let mut out = [0.0; 1];
+        assert_eq!(consumer.read(&mut out), 1);
+        assert_eq!(out, [4.0]);
+        // Skipping doesn't go past what was written
+        consumer.skip(10);
+        assert_eq!(consumer.stats().fill, 0);
+        producer.write(&[5.0]);
+        assert_eq!(consumer.read(&mut out), 1);
+        assert_eq!(out, [5.0]);
+    }
+}
@@ -0,0 +1,29 @@
+/// xorshift32, cheap enough for audio callbacks and plenty random for noise
+/// and simulated packet loss
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// Same seed, same numbers. Zero would only ever give zeros, so it's replaced.
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// Uniform in 0 to 1
+    pub fn unit(&mut self) -> f32 {
+        self.next_u32() as f32 / u32::MAX as f32
+    }
+
+    /// Uniform in -1 to 1
+    pub fn signed(&mut self) -> f32 {
+        self.unit() * 2.0 - 1.0
+    }
+}
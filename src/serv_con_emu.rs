@@ -1,71 +1,145 @@
 use std::{
     collections::VecDeque,
-    sync::mpsc::{Receiver, Sender},
+    sync::{mpsc::Receiver, Arc},
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use audiopus::SampleRate;
+use anyhow::Result;
+use log::{info, warn};
+use uuid::Uuid;
 
-use crate::{ServCon, SpeakerMsg, MicMsg};
+use crate::{
+    codec::CodecOptions,
+    controls::Controls,
+    mixer::Mixer,
+    ring::Producer,
+    rng::Rng,
+    transmit::{Outgoing, Transmitter},
+    Command, MicMsg, ServCon,
+};
 
-pub struct ServEmu {}
+/// Network conditions the loopback pretends to go through
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoopbackOptions {
+    /// One-way delay, ms
+    pub delay_ms: f32,
+    /// Extra delay of up to this much, random per packet, ms
+    pub jitter_ms: f32,
+    /// Share of packets which never arrive, %
+    pub loss_percent: f32,
+}
 
-impl ServEmu {
-    pub fn new() -> Self {
-        Self {}
+/// Packets on their way back to us, by arrival time
+struct Network {
+    options: LoopbackOptions,
+    in_flight: VecDeque<(Instant, Outgoing)>,
+    rng: Rng,
+}
+
+impl Network {
+    fn send(&mut self, packet: Outgoing) {
+        if self.rng.unit() * 100.0 < self.options.loss_percent {
+            return;
+        }
+        let delay = self.options.delay_ms + self.rng.unit() * self.options.jitter_ms;
+        let arrival = Instant::now() + Duration::from_secs_f32(delay / 1000.0);
+        // Later packets may overtake earlier ones, which the jitter buffer sorts out
+        let position = self
+            .in_flight
+            .partition_point(|(other, _)| *other <= arrival);
+        self.in_flight.insert(position, (arrival, packet));
+    }
+
+    /// Takes the next packet which has arrived by now
+    fn receive(&mut self) -> Option<Outgoing> {
+        match self.in_flight.front() {
+            Some((arrival, _)) if *arrival <= Instant::now() => {
+                self.in_flight.pop_front().map(|(_, packet)| packet)
+            }
+            _ => None,
+        }
     }
 }
 
-const OPUS_BUF_SIZE: usize = 960;
+/// Plays our own audio back through the codec and the jitter buffer, without
+/// a server. Handy to hear what the microphone and codec settings sound like.
+pub struct ServEmu {
+    codec: CodecOptions,
+    options: LoopbackOptions,
+    controls: Arc<Controls>,
+}
+
+impl ServEmu {
+    pub fn new(codec: CodecOptions, options: LoopbackOptions, controls: Arc<Controls>) -> Self {
+        Self {
+            codec,
+            options,
+            controls,
+        }
+    }
+}
 
 impl ServCon for ServEmu {
-    fn run(self, tx: Sender<SpeakerMsg>, rx: Receiver<MicMsg>) -> JoinHandle<()> {
+    fn channels(&self) -> usize {
+        if self.codec.stereo {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn run(self, playback: Producer, rx: Receiver<MicMsg>) -> JoinHandle<Result<()>> {
+        let channels = self.channels();
+        let frame_size = self.codec.frame_size as usize;
+
         std::thread::Builder::new()
             .name("ServCon".into())
             .spawn(move || {
-                let encoder = audiopus::coder::Encoder::new(
-                    SampleRate::Hz48000,
-                    audiopus::Channels::Mono,
-                    audiopus::Application::Voip,
-                )
-                .expect("Can't build Opus encoder");
-                let mut decoder =
-                    audiopus::coder::Decoder::new(SampleRate::Hz48000, audiopus::Channels::Mono)
-                        .expect("Can't build Opus decoder");
+                info!("Loopback through {:?}", self.options);
+                let mut transmitter =
+                    Transmitter::new(&self.codec, channels, frame_size, self.controls.clone())?;
+                let mut mixer = Mixer::new(channels);
+                let mut network = Network {
+                    options: self.options,
+                    in_flight: VecDeque::new(),
+                    rng: Rng::new(0x2545_f491),
+                };
+                // We hear ourselves as a participant without an id
+                let id = Uuid::nil();
 
-                let mut total_mic_buf: VecDeque<f32> = VecDeque::new();
                 while let Ok(msg) = rx.recv() {
                     match msg {
                         MicMsg::AudioFromMic(audio_buf) => {
-                            total_mic_buf.extend(audio_buf.iter());
+                            while let Some(packet) = network.receive() {
+                                match packet {
+                                    Outgoing::Audio(frame) => mixer.push(id, frame),
+                                    Outgoing::EndOfTalk => mixer.end_of_talk(&id),
+                                }
+                            }
+                            // Playback is paced by the microphone clock
+                            let mut mixed = mixer.mix(audio_buf.len());
+                            if self.controls.deafened() {
+                                mixed.iter_mut().for_each(|smp| *smp = 0.0);
+                            }
+                            playback.write(&mixed);
+                            for packet in transmitter.push(&audio_buf) {
+                                network.send(packet);
+                            }
                         }
-                        MicMsg::Shutdown => break,
-                    }
-
-                    while total_mic_buf.len() >= OPUS_BUF_SIZE {
-                        let mut net_buf = vec![0; 1024 * 1024];
-                        let mut for_opus = vec![];
-
-                        for _ in 0..OPUS_BUF_SIZE {
-                            let smp = total_mic_buf
-                                .pop_front()
-                                .expect("Not enough in total_mic_buf");
-                            for_opus.push(smp);
+                        MicMsg::Command(Command::ShowStats) => {
+                            info!("Playback: {:?}", playback.stats());
+                            for (_, stats) in mixer.stats() {
+                                info!("Loopback: {}", stats);
+                            }
                         }
-
-                        let enc_pkt_len = encoder
-                            .encode_float(&for_opus, &mut net_buf)
-                            .expect("Can't encode");
-
-                        let mut audio_output: Vec<f32> = vec![0.0; OPUS_BUF_SIZE];
-                        let decoded_len = decoder
-                            .decode_float(Some(&net_buf[..enc_pkt_len]), &mut audio_output, false)
-                            .expect("Can't decode");
-
-                        tx.send(SpeakerMsg::AudioFromSrv(audio_output))
-                            .expect("Can't send");
+                        MicMsg::Command(_) => {
+                            warn!("Not connected to a server in loopback mode");
+                        }
+                        MicMsg::Shutdown => break,
                     }
                 }
+                Ok(())
             })
             .expect("Can't spawn ServCon thread")
     }
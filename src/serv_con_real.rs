@@ -1,160 +1,654 @@
 use std::{
-    collections::VecDeque,
-    net::TcpStream,
-    sync::mpsc::{Receiver, Sender, self},
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{Shutdown, TcpStream},
+    sync::{mpsc::{Receiver, RecvTimeoutError, Sender, self}, Arc},
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use audiopus::{SampleRate, Bitrate};
-use discurse::protocol::{ServerMsg, FromMsg, Gone, socket_reader, ClientMsg, write_msg};
-use log::info;
+use anyhow::{anyhow, bail, Context, Result};
+use discurse::keepalive::Keepalive;
+use discurse::protocol::{ServerMsg, FromMsg, Gone, socket_reader, ClientMsg, try_write_msg, ClientDescription, read_msg, Capabilities, Codec, SessionParams, PROTOCOL_VERSION, read_preamble, write_preamble, valid_nickname, valid_room_name};
+use log::{info, warn};
 use uuid::Uuid;
 
-use crate::{MicMsg, ServCon, SpeakerMsg};
+use crate::{codec::CodecOptions, config::{ClientConfig, UserSettings}, controls::Controls, mixer::Mixer, ring::Producer, transmit::{Outgoing, Transmitter}, Command, MicMsg, ServCon};
 
+/// How long the server gets to answer our Hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first reconnection attempt, doubled after each failed one
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(8);
 
 enum Incoming {
     NewPacket(ServerMsg),
     ServerGone,
 }
 
-impl FromMsg<ServerMsg> for Incoming {
-    fn from_msg(client_id: Option<Uuid>, msg: ServerMsg) -> Self {
-        Self::NewPacket(msg)
+/// Who is in the call, as last reported by the server.
+#[derive(Default)]
+struct Roster {
+    clients: HashMap<Uuid, ClientDescription>,
+    /// Clients in the middle of a talk spurt
+    speaking: HashSet<Uuid>,
+    /// Clients which lost their connection and may come back
+    reconnecting: HashSet<Uuid>,
+}
+
+impl Roster {
+    fn update(&mut self, clients: Vec<ClientDescription>) {
+        self.clients = clients
+            .into_iter()
+            .map(|client| (client.uuid.into(), client))
+            .collect();
+        let clients = &self.clients;
+        self.speaking.retain(|id| clients.contains_key(id));
+        self.reconnecting.retain(|id| clients.contains_key(id));
+    }
+
+    fn joined(&mut self, client: ClientDescription) {
+        self.clients.insert(client.uuid.into(), client);
+    }
+
+    fn left(&mut self, id: Uuid) {
+        self.clients.remove(&id);
+        self.speaking.remove(&id);
+        self.reconnecting.remove(&id);
+    }
+
+    fn reconnecting(&mut self, id: Uuid, reconnecting: bool) {
+        if reconnecting {
+            self.reconnecting.insert(id);
+            self.speaking.remove(&id);
+        } else {
+            self.reconnecting.remove(&id);
+        }
+    }
+
+    fn speaking(&mut self, id: Uuid, speaking: bool) {
+        if speaking {
+            self.speaking.insert(id);
+        } else {
+            self.speaking.remove(&id);
+        }
+    }
+
+    fn renamed(&mut self, id: Uuid, nickname: String) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.nickname = Some(nickname);
+        }
+    }
+
+    fn status_changed(&mut self, id: Uuid, muted: bool, deafened: bool) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.muted = muted;
+            client.deafened = deafened;
+        }
+    }
+
+    fn nickname(&self, id: &Uuid) -> Option<&str> {
+        self.clients.get(id)?.nickname.as_deref()
+    }
+
+    /// Finds a participant by nickname or uuid
+    fn find(&self, user: &str) -> Option<Uuid> {
+        self.clients
+            .iter()
+            .find(|(id, client)| client.nickname.as_deref() == Some(user) || id.to_string() == user)
+            .map(|(&id, _)| id)
+    }
+
+    fn name(&self, id: &Uuid) -> String {
+        match self.clients.get(id).and_then(|client| client.nickname.as_ref()) {
+            Some(nickname) => nickname.clone(),
+            None => id.to_string(),
+        }
     }
 }
 
-impl Gone for Incoming {
-    fn gone(client_id: Option<Uuid>) -> Self {
-        Self::ServerGone
+impl fmt::Display for Roster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in call", self.clients.len())?;
+        for (id, client) in &self.clients {
+            let nickname = client.nickname.as_deref().unwrap_or("<anonymous>");
+            write!(f, "\n  {} {}", id, nickname)?;
+            if let Some(rtt) = client.rtt_ms {
+                write!(f, " {} ms", rtt)?;
+            }
+            if self.speaking.contains(id) {
+                write!(f, " (speaking)")?;
+            }
+            if self.reconnecting.contains(id) {
+                write!(f, " (reconnecting)")?;
+            }
+            if client.deafened {
+                write!(f, " (deafened)")?;
+            } else if client.muted {
+                write!(f, " (muted)")?;
+            }
+        }
+        Ok(())
     }
 }
 
 enum Event {
     Incoming(Incoming),
     MicMsg(MicMsg),
+    /// We are back in after losing the connection
+    Reconnected(Connection),
+    /// We can't go on in the session we had
+    ReconnectFailed(anyhow::Error),
 }
 
-pub struct ServReal {
+impl FromMsg<ServerMsg> for Event {
+    fn from_msg(_client_id: Option<Uuid>, msg: ServerMsg) -> Self {
+        Self::Incoming(Incoming::NewPacket(msg))
+    }
+}
+
+impl Gone for Event {
+    fn gone(_client_id: Option<Uuid>) -> Self {
+        Self::Incoming(Incoming::ServerGone)
+    }
+}
+
+/// Applies the local volume and mute remembered for a participant's nickname,
+/// unless we already play them somehow
+fn restore_user(mixer: &mut Mixer, config: &ClientConfig, id: Uuid, nickname: Option<&str>) {
+    if mixer.has_user(&id) {
+        return;
+    }
+    let settings = nickname
+        .and_then(|nickname| config.users.get(nickname))
+        .copied()
+        .unwrap_or_default();
+    mixer.set_user(id, settings);
+}
+
+/// Changes how we play a participant and remembers it by their nickname
+fn update_user(
+    roster: &Roster,
+    mixer: &mut Mixer,
+    config: &mut ClientConfig,
+    user: &str,
+    change: impl FnOnce(&mut UserSettings),
+) {
+    let id = match roster.find(user) {
+        Some(id) => id,
+        None => {
+            warn!("No one called {} in the room", user);
+            return;
+        }
+    };
+    let mut settings = mixer.user(&id);
+    change(&mut settings);
+    mixer.set_user(id, settings);
+    info!(
+        "{} plays at {:.0}%{}",
+        roster.name(&id),
+        settings.volume * 100.0,
+        if settings.muted { ", muted" } else { "" }
+    );
+
+    if let Some(nickname) = roster.nickname(&id) {
+        remember_user(config, nickname, settings);
+    }
+}
+
+fn remember_user(config: &mut ClientConfig, nickname: &str, settings: UserSettings) {
+    config.users.insert(nickname.to_owned(), settings);
+    if let Err(err) = config.save() {
+        warn!("{:#}", err);
+    }
+}
+
+/// A connection the server has welcomed us on
+struct Connection {
     stream: TcpStream,
-    tx: Sender<Incoming>,
-    rx: Receiver<Incoming>,
+    uuid: Uuid,
+    /// Resumes the session after losing the connection
+    token: Uuid,
+    resumed: bool,
+    params: SessionParams,
 }
 
-impl ServReal {
-    pub fn new(addr: String) -> Self {
-        let stream = TcpStream::connect(addr).expect("Can't connect");
-        let (tx, rx) = mpsc::channel();
-        let stream_clone = stream.try_clone().expect("Can't clone stream");
-        let tx_clone = tx.clone();
-        std::thread::spawn(move || {
-            socket_reader(stream_clone, None, tx_clone)
-        });
-        Self { stream, tx, rx }
+fn hello(codec: &CodecOptions, nickname: Option<String>, resume: Option<Uuid>) -> ClientMsg {
+    ClientMsg::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: String::from(env!("CARGO_PKG_NAME")),
+        client_version: String::from(env!("CARGO_PKG_VERSION")),
+        nickname,
+        capabilities: Capabilities {
+            codecs: vec![Codec::Opus],
+            stereo: codec.stereo,
+            frame_sizes: vec![codec.frame_size],
+        },
+        encoder: codec.encoder.clone(),
+        resume: resume.map(Into::into),
     }
 }
 
-// const OPUS_BUF_SIZE: usize = 960;
-const OPUS_BUF_SIZE: usize = 2880;
+fn connect(addr: &str, hello: ClientMsg) -> Result<Connection> {
+    let mut stream =
+        TcpStream::connect(addr).with_context(|| format!("Can't connect to {}", addr))?;
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .context("Can't set read timeout")?;
+    // Small messages would otherwise wait on the ACK of the previous one,
+    // holding back audio and skewing the round trips
+    stream.set_nodelay(true).context("Can't disable Nagle")?;
 
-fn serv_redir(srx: Receiver<Incoming>, etx: Sender<Event>) {
-    while let Ok(msg) = srx.recv() {
-        etx.send(Event::Incoming(msg)).expect("Can't resend srx -> etx");
+    write_preamble::<ClientMsg>(&mut stream)?;
+    read_preamble::<ServerMsg>(&mut stream).context("Can't talk to server")?;
+    try_write_msg(&mut stream, hello)?;
+
+    let connection = match read_msg(&mut stream, &mut vec![])? {
+        ServerMsg::Welcome {
+            protocol_version,
+            uuid,
+            params,
+            token,
+            resumed,
+        } => {
+            info!(
+                "Joined as {}, server protocol version {}, session {:?}",
+                Uuid::from(uuid),
+                protocol_version,
+                params
+            );
+            Connection {
+                stream,
+                uuid: uuid.into(),
+                token: token.into(),
+                resumed,
+                params,
+            }
+        }
+        ServerMsg::Bye { reason } => bail!("Server refused us: {}", reason),
+        msg => bail!("Unexpected reply to Hello: {:?}", msg),
+    };
+    connection
+        .stream
+        .set_read_timeout(None)
+        .context("Can't reset read timeout")?;
+    Ok(connection)
+}
+
+/// Keeps trying to get back into the session `token` belongs to, backing off
+/// exponentially, until it works or the ServCon is gone. Gives up if the server
+/// now wants different session parameters.
+fn reconnect(
+    addr: String,
+    codec: CodecOptions,
+    nickname: Option<String>,
+    token: Uuid,
+    params: SessionParams,
+    etx: Sender<Event>,
+) {
+    let mut backoff = RECONNECT_BACKOFF;
+    loop {
+        std::thread::sleep(backoff);
+        match connect(&addr, hello(&codec, nickname.clone(), Some(token))) {
+            // Capture and playback are set up for the old parameters
+            Ok(mut connection) if connection.params != params => {
+                // Whatever session we got is of no use, so don't leave it hanging around
+                let _ = try_write_msg(&mut connection.stream, ClientMsg::Leave);
+                let err = anyhow!(
+                    "Server changed the session from {:?} to {:?}",
+                    params,
+                    connection.params
+                );
+                let _ = etx.send(Event::ReconnectFailed(err));
+                return;
+            }
+            Ok(connection) => {
+                let _ = etx.send(Event::Reconnected(connection));
+                return;
+            }
+            Err(err) => warn!("{:#}, retrying in {:?}", err, backoff),
+        }
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
+
+pub struct ServReal {
+    addr: String,
+    nickname: Option<String>,
+    /// `None` while reconnecting
+    stream: Option<TcpStream>,
+    token: Uuid,
+    params: SessionParams,
+    codec: CodecOptions,
+    controls: Arc<Controls>,
+    config: ClientConfig,
+}
+
+impl ServReal {
+    pub fn new(
+        addr: String,
+        nickname: Option<String>,
+        codec: CodecOptions,
+        controls: Arc<Controls>,
+        config: ClientConfig,
+    ) -> Result<Self> {
+        let connection = connect(&addr, hello(&codec, nickname.clone(), None))?;
+        Ok(Self {
+            addr,
+            nickname,
+            stream: Some(connection.stream),
+            token: connection.token,
+            params: connection.params,
+            codec,
+            controls,
+            config,
+        })
+    }
+
+    /// Writes to the server unless we are reconnecting, dropping the connection
+    /// if that fails
+    fn send(&mut self, msg: ClientMsg) {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return,
+        };
+        if let Err(err) = try_write_msg(stream, msg) {
+            warn!("{:#}", err);
+            self.disconnect();
+        }
+    }
+
+    /// Gives up on the connection, the reader then reports the server as gone
+    fn disconnect(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
     }
 }
 
+/// Reads what the server sends into the ServCon's events
+fn listen(stream: &TcpStream, etx: Sender<Event>) {
+    let stream = stream.try_clone().expect("Can't clone stream");
+    std::thread::spawn(move || socket_reader(stream, None, etx));
+}
+
 fn mic_redir(srx: Receiver<MicMsg>, etx: Sender<Event>) {
     while let Ok(msg) = srx.recv() {
-        etx.send(Event::MicMsg(msg)).expect("Can't resend srx -> etx");
+        // The ServCon may have stopped on its own, leaving the capture nobody to talk to
+        if etx.send(Event::MicMsg(msg)).is_err() {
+            break;
+        }
     }
 }
 
 impl ServCon for ServReal {
-    fn run(mut self, tx: Sender<SpeakerMsg>, rx: Receiver<MicMsg>) -> JoinHandle<()> {
-        let (etx, erx) = mpsc::channel();
-        let etx_clone = etx.clone();
-
-        std::thread::spawn(|| {
-            serv_redir(self.rx, etx_clone)
-        });
+    /// Channels the server settled on
+    fn channels(&self) -> usize {
+        self.params.channels as usize
+    }
 
+    fn run(mut self, playback: Producer, rx: Receiver<MicMsg>) -> JoinHandle<Result<()>> {
+        let (etx, erx) = mpsc::channel();
+        if let Some(stream) = &self.stream {
+            listen(stream, etx.clone());
+        }
+        let mic_etx = etx.clone();
         std::thread::spawn(|| {
-            mic_redir(rx, etx)
+            mic_redir(rx, mic_etx)
         });
 
-        // let quality = audiopus::Application::Voip;
-        let quality = audiopus::Application::Voip;
-        let samplerate = SampleRate::Hz48000;
+        let frame_size = self.params.frame_size as usize;
+        let channels = self.params.channels as usize;
 
         std::thread::Builder::new()
             .name("ServCon".into())
             .spawn(move || {
-                let encoder = audiopus::coder::Encoder::new(
-                    samplerate,
-                    audiopus::Channels::Mono,
-                    quality,
-                )
-                .expect("Can't build Opus encoder");
-                let mut decoder =
-                    audiopus::coder::Decoder::new(samplerate, audiopus::Channels::Mono)
-                        .expect("Can't build Opus decoder");
-
-                let mut total_mic_buf: VecDeque<f32> = VecDeque::new();
-
-                while let Ok(msg) = erx.recv() {
+                let mut transmitter = Transmitter::new(&self.codec, channels, frame_size, self.controls.clone())?;
+                let mut mixer = Mixer::new(channels);
+                // Mute and deafen as last told to the server
+                let mut announced = (false, false);
+
+                let mut roster = Roster::default();
+                let mut keepalive = Keepalive::default();
+
+                loop {
+                    // Pings go out on their own schedule, whether or not there is audio
+                    let msg = match self.stream.is_some().then(|| keepalive.next_ping()) {
+                        Some(next_ping) => erx.recv_timeout(next_ping.saturating_duration_since(Instant::now())),
+                        None => erx.recv().map_err(RecvTimeoutError::from),
+                    };
+                    if self.stream.is_some() {
+                        if let Some(timestamp) = keepalive.ping(Instant::now()) {
+                            self.send(ClientMsg::Ping(timestamp));
+                        }
+                        if keepalive.dead() {
+                            warn!("Server stopped answering pings");
+                            self.disconnect();
+                        }
+                    }
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
                     match msg {
                         Event::Incoming(inc) => match inc {
                             Incoming::NewPacket(pkt) => match pkt {
-                                ServerMsg::Version(version) => {
-                                    info!("Server protocol version {}", version);
+                                ServerMsg::Welcome { .. } => {
+                                    warn!("Server repeated Welcome, ignoring");
                                 },
-                                ServerMsg::Clients(_) => unimplemented!(),
-                                ServerMsg::OpusAudio(id, audio) => {
-                                    let mut audio_output: Vec<f32> = vec![0.0; OPUS_BUF_SIZE];
-                                    let decoded_len = decoder
-                                        .decode_float(Some(&audio), &mut audio_output, false)
-                                        .expect("Can't decode");
-
-                                    tx.send(SpeakerMsg::AudioFromSrv(audio_output))
-                                        .expect("Can't send");
+                                ServerMsg::Clients(clients) => {
+                                    for client in &clients {
+                                        let nickname = client.nickname.as_deref();
+                                        restore_user(&mut mixer, &self.config, client.uuid.into(), nickname);
+                                    }
+                                    roster.update(clients);
+                                    info!("{}", roster);
+                                },
+                                ServerMsg::OpusAudio(id, frame) => {
+                                    roster.speaking(id.into(), true);
+                                    mixer.push(id.into(), frame);
+                                },
+                                ServerMsg::EndOfTalk(id) => {
+                                    roster.speaking(id.into(), false);
+                                    mixer.end_of_talk(&id.into());
+                                },
+                                ServerMsg::StatusChanged { uuid, muted, deafened } => {
+                                    let id = uuid.into();
+                                    roster.status_changed(id, muted, deafened);
+                                    let status = match (muted, deafened) {
+                                        (_, true) => "deafened",
+                                        (true, false) => "muted",
+                                        (false, false) => "listening and talking",
+                                    };
+                                    info!("{} is {}", roster.name(&id), status);
                                 },
                                 ServerMsg::Bye { reason } => {
                                     info!("Server said bye. Reason: {}", reason);
                                 },
+                                ServerMsg::ClientJoined(client) => {
+                                    let id = client.uuid.into();
+                                    restore_user(&mut mixer, &self.config, id, client.nickname.as_deref());
+                                    roster.joined(client);
+                                    info!("{} joined", roster.name(&id));
+                                },
+                                ServerMsg::ClientLeft { uuid, reason } => {
+                                    let id = uuid.into();
+                                    let name = roster.name(&id);
+                                    roster.left(id);
+                                    mixer.remove(&id);
+                                    info!("{} left ({:?})", name, reason);
+                                },
+                                ServerMsg::NicknameChanged { uuid, nickname } => {
+                                    let id = uuid.into();
+                                    let old = roster.name(&id);
+                                    // We play them as before, so that's what their new name remembers
+                                    let settings = mixer.user(&id);
+                                    if settings != UserSettings::default() || self.config.users.contains_key(&nickname) {
+                                        remember_user(&mut self.config, &nickname, settings);
+                                    }
+                                    roster.renamed(id, nickname.clone());
+                                    info!("{} is now known as {}", old, nickname);
+                                },
+                                ServerMsg::Rooms(rooms) => {
+                                    for room in rooms {
+                                        info!("Room {}: {} in call", room.name, room.clients);
+                                    }
+                                },
+                                ServerMsg::RoomJoined(room) => {
+                                    info!("Now in room {}", room);
+                                    mixer.clear();
+                                },
+                                ServerMsg::RoomError(reason) => {
+                                    warn!("{}", reason);
+                                },
+                                ServerMsg::EchoTest(Some(delay_ms)) => {
+                                    info!("Echo test, hearing ourselves {} ms later", delay_ms);
+                                },
+                                ServerMsg::EchoTest(None) => {
+                                    info!("Echo test is over");
+                                },
+                                ServerMsg::ClientReconnecting(uuid) => {
+                                    let id = uuid.into();
+                                    roster.reconnecting(id, true);
+                                    mixer.end_of_talk(&id);
+                                    info!("{} is reconnecting", roster.name(&id));
+                                },
+                                ServerMsg::ClientReconnected(uuid) => {
+                                    let id = uuid.into();
+                                    roster.reconnecting(id, false);
+                                    info!("{} is back", roster.name(&id));
+                                },
+                                ServerMsg::Ping(timestamp) => {
+                                    self.send(ClientMsg::Pong(timestamp));
+                                },
+                                ServerMsg::Pong(timestamp) => {
+                                    keepalive.pong(Instant::now(), timestamp);
+                                },
+                            },
+                            Incoming::ServerGone => {
+                                warn!("Lost connection to the server, reconnecting");
+                                self.stream = None;
+                                transmitter.interrupt();
+                                mixer.clear();
+                                let addr = self.addr.clone();
+                                let codec = self.codec.clone();
+                                let nickname = self.nickname.clone();
+                                let token = self.token;
+                                let params = self.params.clone();
+                                let etx = etx.clone();
+                                std::thread::Builder::new()
+                                    .name("Reconnect".into())
+                                    .spawn(move || reconnect(addr, codec, nickname, token, params, etx))
+                                    .expect("Can't spawn reconnect thread");
                             },
-                            Incoming::ServerGone => break,
                         },
+                        Event::Reconnected(connection) => {
+                            if connection.resumed {
+                                info!("Reconnected, session resumed");
+                            } else {
+                                info!("Reconnected as {}, the old session had expired", connection.uuid);
+                                // The new session starts out unmuted
+                                announced = (false, false);
+                            }
+                            listen(&connection.stream, etx.clone());
+                            keepalive = Keepalive::default();
+                            self.stream = Some(connection.stream);
+                            self.token = connection.token;
+                        },
+                        Event::ReconnectFailed(err) => return Err(err),
                         Event::MicMsg(mic_msg) => {
                             match mic_msg {
                                 MicMsg::AudioFromMic(audio_buf) => {
-                                    total_mic_buf.extend(audio_buf.iter());
-                                }
-                                MicMsg::Shutdown => break,
-                            };
-
-                            while total_mic_buf.len() >= OPUS_BUF_SIZE {
-                                let mut net_buf = vec![0; 1024 * 1024];
-                                let mut for_opus = vec![];
+                                    // Playback is paced by the microphone clock
+                                    let mut mixed = mixer.mix(audio_buf.len());
+                                    if self.controls.deafened() {
+                                        mixed.iter_mut().for_each(|smp| *smp = 0.0);
+                                    }
+                                    playback.write(&mixed);
+                                    for packet in transmitter.push(&audio_buf) {
+                                        self.send(match packet {
+                                            Outgoing::Audio(frame) => ClientMsg::OpusAudio(frame),
+                                            Outgoing::EndOfTalk => ClientMsg::EndOfTalk,
+                                        });
+                                    }
 
-                                for _ in 0..OPUS_BUF_SIZE {
-                                    let smp = total_mic_buf
-                                        .pop_front()
-                                        .expect("Not enough in total_mic_buf");
-                                    for_opus.push(smp);
+                                    let status = (self.controls.muted(), self.controls.deafened());
+                                    if status != announced && self.stream.is_some() {
+                                        announced = status;
+                                        let (muted, deafened) = status;
+                                        self.send(ClientMsg::Status { muted, deafened });
+                                    }
                                 }
-
-                                let enc_pkt_len = encoder
-                                    .encode_float(&for_opus, &mut net_buf)
-                                    .expect("Can't encode");
-
-                                let minimal_net_buf = net_buf[0..enc_pkt_len].to_vec();
-
-                                let msg = ClientMsg::OpusAudio(minimal_net_buf);
-                                write_msg(&mut self.stream, msg);
-                            }
+                                MicMsg::Command(cmd) => {
+                                    let local = matches!(
+                                        cmd,
+                                        Command::ShowClients | Command::ShowStats | Command::Volume(..) | Command::MuteUser(_)
+                                    );
+                                    if !local && self.stream.is_none() {
+                                        warn!("Not connected to the server, still reconnecting");
+                                        continue;
+                                    }
+                                    let msg = match cmd {
+                                        // The roster is printed once the server has sent fresh round trips
+                                        Command::ShowClients if self.stream.is_some() => ClientMsg::GetClients,
+                                        Command::ShowClients => {
+                                            info!("Still reconnecting, last known {}", roster);
+                                            continue;
+                                        }
+                                        Command::ShowStats => {
+                                            match keepalive.rtt() {
+                                                Some(rtt) => info!("Round trip to the server: {:.1} ms", rtt.as_secs_f64() * 1000.0),
+                                                None => info!("Round trip to the server not measured yet"),
+                                            }
+                                            info!("Playback: {:?}", playback.stats());
+                                            for (id, stats) in mixer.stats() {
+                                                info!("{}: {}", roster.name(&id), stats);
+                                            }
+                                            continue;
+                                        }
+                                        Command::Volume(user, volume) => {
+                                            update_user(&roster, &mut mixer, &mut self.config, &user, |settings| {
+                                                settings.volume = volume
+                                            });
+                                            continue;
+                                        }
+                                        Command::MuteUser(user) => {
+                                            update_user(&roster, &mut mixer, &mut self.config, &user, |settings| {
+                                                settings.muted = !settings.muted
+                                            });
+                                            continue;
+                                        }
+                                        Command::Nickname(nickname) if !valid_nickname(&nickname) => {
+                                            warn!("Invalid nickname {:?}", nickname);
+                                            continue;
+                                        }
+                                        Command::Nickname(nickname) => {
+                                            // Carried over into a new session if the old one expires
+                                            self.nickname = Some(nickname.clone());
+                                            ClientMsg::Nickname(nickname)
+                                        }
+                                        Command::ListRooms => ClientMsg::ListRooms,
+                                        Command::CreateRoom(room) if !valid_room_name(&room) => {
+                                            warn!("Invalid room name {:?}", room);
+                                            continue;
+                                        }
+                                        Command::CreateRoom(room) => ClientMsg::CreateRoom(room),
+                                        Command::JoinRoom(room) => ClientMsg::JoinRoom(room),
+                                        Command::EchoTest(delay_ms) => ClientMsg::EchoTest(delay_ms),
+                                    };
+                                    self.send(msg);
+                                }
+                                MicMsg::Shutdown => {
+                                    // Frees our place right away instead of having it held for a reconnect
+                                    self.send(ClientMsg::Leave);
+                                    break;
+                                }
+                            };
                         }
                     }
                 }
+                Ok(())
             })
             .expect("Can't spawn ServCon thread")
     }
@@ -1,7 +1,8 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, VecDeque},
     net::{Shutdown, TcpListener, TcpStream},
-    sync::mpsc::{self, Receiver, Sender},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
@@ -9,19 +10,112 @@ use fast_log::Config;
 use log::{info, warn};
 use uuid::Uuid;
 
-use discurse::protocol::{ClientMsg, ServerMsg, write_msg, FromMsg, Gone, socket_reader};
+use discurse::keepalive::Keepalive;
+use discurse::protocol::{ClientMsg, ServerMsg, write_msg, FromMsg, Gone, socket_reader, ClientDescription, LeaveReason, RoomDescription, LOBBY, read_msg, Capabilities, SessionParams, Codec, PROTOCOL_VERSION, OPUS_FRAME_SIZES, read_preamble, write_preamble, AudioFrame, try_write_msg, valid_nickname, valid_room_name};
 
-const PROTOCOL_VERSION: u64 = 1;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest an echo test may hold audio back
+const MAX_ECHO_DELAY_MS: u32 = 5000;
+/// How long a client which lost its connection may come back and resume its session
+const RESUME_GRACE: Duration = Duration::from_secs(30);
 
-fn client_writer(mut stream: TcpStream, cwrx: Receiver<ToClient>) {
-    let msg = ServerMsg::Version(PROTOCOL_VERSION);
-    write_msg(&mut stream, msg);
+/// Picks session parameters both sides support
+fn negotiate(capabilities: &Capabilities) -> Result<SessionParams, String> {
+    if !capabilities.codecs.contains(&Codec::Opus) {
+        return Err(String::from("No supported codec offered"));
+    }
+    let frame_size = capabilities
+        .frame_sizes
+        .iter()
+        .find(|size| OPUS_FRAME_SIZES.contains(size))
+        .ok_or_else(|| String::from("No supported frame size offered"))?;
+    Ok(SessionParams {
+        codec: Codec::Opus,
+        channels: if capabilities.stereo { 2 } else { 1 },
+        frame_size: *frame_size,
+    })
+}
+
+/// What the client asked for in its Hello
+struct Greeting {
+    nickname: Option<String>,
+    /// Token of the session to resume
+    resume: Option<Uuid>,
+    params: SessionParams,
+}
+
+/// Waits for the client's Hello and settles the session parameters, the
+/// Welcome is up to the broadcaster letting the client in
+fn handshake(stream: &mut TcpStream, id: Uuid) -> Result<Greeting, String> {
+    match read_msg::<ClientMsg>(stream, &mut vec![]) {
+        Ok(ClientMsg::Hello {
+            protocol_version,
+            client_name,
+            client_version,
+            nickname,
+            capabilities,
+            encoder,
+            resume,
+        }) => {
+            info!(
+                "Connection {} is {} {}, protocol version {}",
+                id, client_name, client_version, protocol_version
+            );
+            info!("Connection {} encoder: {:?}", id, encoder);
+            if protocol_version != PROTOCOL_VERSION {
+                return Err(format!(
+                    "Protocol version {} is not supported, server speaks {}",
+                    protocol_version, PROTOCOL_VERSION
+                ));
+            }
+            if let Some(nickname) = nickname.as_deref().filter(|nickname| !valid_nickname(nickname)) {
+                return Err(format!("Invalid nickname {:?}", nickname));
+            }
+            let params = negotiate(&capabilities)?;
+            info!("Connection {} session: {:?}", id, params);
+            Ok(Greeting {
+                nickname,
+                resume: resume.map(Uuid::from),
+                params,
+            })
+        }
+        Ok(_) => Err(String::from("Expected Hello")),
+        Err(err) => Err(format!("{:#}", err)),
+    }
+}
 
+fn client_writer(mut stream: TcpStream, cwrx: Receiver<ToClient>) {
     while let Ok(msg) = cwrx.recv() {
-        match msg {
-            ToClient::Audio(id, audio) => {
-                let msg = ServerMsg::OpusAudio(id.into(), audio);
-                write_msg(&mut stream, msg);
+        let msg = match msg {
+            ToClient::Audio(id, frame) => ServerMsg::OpusAudio(id.into(), frame),
+            ToClient::Clients(clients) => ServerMsg::Clients(clients),
+            ToClient::Joined(client) => ServerMsg::ClientJoined(client),
+            ToClient::Left(id, reason) => ServerMsg::ClientLeft {
+                uuid: id.into(),
+                reason,
+            },
+            ToClient::NicknameChanged(id, nickname) => ServerMsg::NicknameChanged {
+                uuid: id.into(),
+                nickname,
+            },
+            ToClient::Rooms(rooms) => ServerMsg::Rooms(rooms),
+            ToClient::RoomJoined(room) => ServerMsg::RoomJoined(room),
+            ToClient::RoomError(reason) => ServerMsg::RoomError(reason),
+            ToClient::EndOfTalk(id) => ServerMsg::EndOfTalk(id.into()),
+            ToClient::StatusChanged(id, muted, deafened) => ServerMsg::StatusChanged {
+                uuid: id.into(),
+                muted,
+                deafened,
+            },
+            ToClient::EchoTest(delay_ms) => ServerMsg::EchoTest(delay_ms),
+            ToClient::Reconnecting(id) => ServerMsg::ClientReconnecting(id.into()),
+            ToClient::Reconnected(id) => ServerMsg::ClientReconnected(id.into()),
+            ToClient::Ping(timestamp) => ServerMsg::Ping(timestamp),
+            ToClient::Pong(timestamp) => ServerMsg::Pong(timestamp),
+            ToClient::Close => {
+                // Wakes up the reader, which reports the connection as gone
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
             }
             ToClient::Shutdown => {
                 let msg = ServerMsg::Bye {
@@ -31,13 +125,35 @@ fn client_writer(mut stream: TcpStream, cwrx: Receiver<ToClient>) {
                 stream
                     .shutdown(Shutdown::Both)
                     .expect("Can't shutdown stream");
+                continue;
             }
+        };
+        if let Err(err) = try_write_msg(&mut stream, msg) {
+            // Wakes up the reader, which reports the connection as gone
+            warn!("{:#}", err);
+            let _ = stream.shutdown(Shutdown::Both);
+            break;
         }
     }
 }
 
+/// Who a new connection turned out to be
+struct Admission {
+    id: Uuid,
+    /// Lets the client resume its session later on
+    token: Uuid,
+    resumed: bool,
+}
+
+/// Connections are known by their own id, which maps onto the id of the client
+/// using them. The client keeps its id across connections when resuming.
 enum ToBroadcaster {
-    NewClient(Uuid, Sender<ToClient>),
+    NewClient {
+        connection: Uuid,
+        greeting: Greeting,
+        tx: Sender<ToClient>,
+        reply: Sender<Admission>,
+    },
     NewPacket(Uuid, ClientMsg),
     ClientGone(Uuid),
 }
@@ -55,57 +171,438 @@ impl Gone for ToBroadcaster {
 }
 
 enum ToClient {
-    Audio(Uuid, Vec<u8>),
+    Audio(Uuid, AudioFrame),
+    Clients(Vec<ClientDescription>),
+    Joined(ClientDescription),
+    Left(Uuid, LeaveReason),
+    NicknameChanged(Uuid, String),
+    Rooms(Vec<RoomDescription>),
+    RoomJoined(String),
+    RoomError(String),
+    EndOfTalk(Uuid),
+    StatusChanged(Uuid, bool, bool),
+    EchoTest(Option<u32>),
+    Reconnecting(Uuid),
+    Reconnected(Uuid),
+    Ping(u64),
+    Pong(u64),
+    /// Drops the connection without a word, the client isn't listening anyway
+    Close,
+    #[allow(dead_code)]
     Shutdown,
 }
 
 struct Client {
     nickname: Option<String>,
+    room: String,
+    muted: bool,
+    deafened: bool,
+    /// While set, the client's audio goes back to it alone this much later
+    echo_delay: Option<Duration>,
+    /// Connection the client is using right now
+    connection: Uuid,
+    /// Resumes the session if presented in a Hello, the same for the whole
+    /// session so that a Welcome lost along with a connection doesn't matter
+    token: Uuid,
+    /// When the connection was lost, the client's place being held since
+    gone: Option<Instant>,
+    keepalive: Keepalive,
+    tx: Sender<ToClient>,
+}
+
+/// Messages held back by echo tests, by the time they are due
+type Echoes = VecDeque<(Instant, Uuid, ToClient)>;
+
+/// Holds a message back for the client's echo test, if it's running one.
+/// Returns false if it isn't.
+fn echo(clients: &HashMap<Uuid, Client>, echoes: &mut Echoes, id: Uuid, msg: ToClient) -> bool {
+    let delay = match clients.get(&id).and_then(|client| client.echo_delay) {
+        Some(delay) => delay,
+        None => return false,
+    };
+    let due = Instant::now() + delay;
+    // The delay may have been shortened since the last message
+    let position = echoes.partition_point(|(other, ..)| *other <= due);
+    echoes.insert(position, (due, id, msg));
+    true
+}
+
+fn describe_client(id: Uuid, client: &Client) -> ClientDescription {
+    ClientDescription {
+        nickname: client.nickname.clone(),
+        uuid: id.into(),
+        muted: client.muted,
+        deafened: client.deafened,
+        rtt_ms: client.keepalive.rtt().map(|rtt| rtt.as_millis() as u32),
+    }
+}
+
+fn describe_clients(clients: &HashMap<Uuid, Client>, room: &str) -> Vec<ClientDescription> {
+    clients
+        .iter()
+        .filter(|(_, client)| client.room == room)
+        .map(|(&id, client)| describe_client(id, client))
+        .collect()
+}
+
+fn describe_rooms(clients: &HashMap<Uuid, Client>) -> Vec<RoomDescription> {
+    let mut rooms: BTreeMap<&str, u32> = BTreeMap::new();
+    rooms.insert(LOBBY, 0);
+    for client in clients.values() {
+        *rooms.entry(&client.room).or_default() += 1;
+    }
+    rooms
+        .into_iter()
+        .map(|(name, clients)| RoomDescription {
+            name: name.to_owned(),
+            clients,
+        })
+        .collect()
+}
+
+fn room_exists(clients: &HashMap<Uuid, Client>, room: &str) -> bool {
+    room == LOBBY || clients.values().any(|client| client.room == room)
+}
+
+fn room_of(clients: &HashMap<Uuid, Client>, id: Uuid) -> Option<String> {
+    clients.get(&id).map(|client| client.room.clone())
+}
+
+/// Sends a message to a single client, holding its place if it can't be reached
+/// anymore. Clients without a connection miss out.
+fn send_to(clients: &mut HashMap<Uuid, Client>, id: Uuid, msg: ToClient) {
+    let sent = match clients.get(&id) {
+        Some(client) if client.gone.is_none() => client.tx.send(msg),
+        _ => return,
+    };
+    if let Err(err) = sent {
+        warn!("Can't notify client {}: {}", id, err);
+        connection_lost(clients, id);
+    }
+}
+
+/// Sends a message to every connected client in `room` except `skip`, holding
+/// the places of the ones which can't be reached anymore.
+fn broadcast(
+    clients: &mut HashMap<Uuid, Client>,
+    room: &str,
+    skip: Option<Uuid>,
+    msg: &dyn Fn() -> ToClient,
+) {
+    let mut gone = vec![];
+    for (&recv_id, client) in clients.iter() {
+        if Some(recv_id) == skip || client.room != room || client.gone.is_some() {
+            continue;
+        }
+        if let Err(err) = client.tx.send(msg()) {
+            warn!("Can't notify client {}: {}", recv_id, err);
+            gone.push(recv_id);
+        }
+    }
+    for id in gone {
+        connection_lost(clients, id);
+    }
+}
+
+/// Holds the client's place for a while after its connection broke, so that it
+/// can resume its session. The room sees it reconnecting instead of leaving.
+fn connection_lost(clients: &mut HashMap<Uuid, Client>, id: Uuid) {
+    let room = match clients.get_mut(&id) {
+        Some(client) if client.gone.is_none() => {
+            client.gone = Some(Instant::now());
+            client.room.clone()
+        }
+        _ => return,
+    };
+    info!(
+        "Client {} lost its connection, holding its place for {:?}",
+        id, RESUME_GRACE
+    );
+    broadcast(clients, &room, Some(id), &|| ToClient::Reconnecting(id));
+}
+
+/// Lets a client in, picking up the session its token belongs to if it has one
+fn admit(
+    clients: &mut HashMap<Uuid, Client>,
+    connections: &mut HashMap<Uuid, Uuid>,
+    connection: Uuid,
+    greeting: Greeting,
     tx: Sender<ToClient>,
+) -> Admission {
+    let resumed = greeting.resume.and_then(|resume| {
+        clients
+            .iter()
+            .find(|(_, client)| client.token == resume)
+            .map(|(&id, _)| id)
+    });
+    if let Some(id) = resumed {
+        let client = clients.get_mut(&id).expect("Can't find resumed client");
+        // Whatever the old connection still has to say is ignored from now on,
+        // and a half-open one is closed rather than left for TCP to time out
+        connections.remove(&client.connection);
+        connections.insert(connection, id);
+        let _ = client.tx.send(ToClient::Close);
+        client.connection = connection;
+        client.keepalive = Keepalive::default();
+        client.tx = tx;
+        let was_gone = client.gone.take().is_some();
+        let room = client.room.clone();
+        info!("Client {} resumes its session in {}", id, room);
+        if was_gone {
+            broadcast(clients, &room, Some(id), &|| ToClient::Reconnected(id));
+        }
+        send_to(clients, id, ToClient::RoomJoined(room.clone()));
+        let description = describe_clients(clients, &room);
+        send_to(clients, id, ToClient::Clients(description));
+        return Admission {
+            id,
+            token: clients[&id].token,
+            resumed: true,
+        };
+    }
+
+    let id = Uuid::new_v4();
+    let token = Uuid::new_v4();
+    info!("Connection {} is client {}", connection, id);
+    let client = Client {
+        nickname: greeting.nickname,
+        room: LOBBY.to_owned(),
+        muted: false,
+        deafened: false,
+        echo_delay: None,
+        connection,
+        token,
+        gone: None,
+        keepalive: Keepalive::default(),
+        tx,
+    };
+    let description = describe_client(id, &client);
+    broadcast(clients, LOBBY, None, &|| ToClient::Joined(description.clone()));
+    clients.insert(id, client);
+    connections.insert(connection, id);
+    send_to(clients, id, ToClient::RoomJoined(LOBBY.to_owned()));
+    let description = describe_clients(clients, LOBBY);
+    send_to(clients, id, ToClient::Clients(description));
+    Admission {
+        id,
+        token,
+        resumed: false,
+    }
+}
+
+/// When the broadcaster next has something to do on its own
+fn next_deadline(clients: &HashMap<Uuid, Client>, echoes: &Echoes) -> Option<Instant> {
+    let client_deadlines = clients.values().map(|client| match client.gone {
+        Some(gone) => gone + RESUME_GRACE,
+        None => client.keepalive.next_ping(),
+    });
+    echoes.front().map(|(due, ..)| *due).into_iter().chain(client_deadlines).min()
+}
+
+/// Pings the clients which are due, dropping the connections of the ones
+/// which stopped answering
+fn keep_alive(clients: &mut HashMap<Uuid, Client>, now: Instant) {
+    let mut pings = vec![];
+    let mut dead = vec![];
+    for (&id, client) in clients.iter_mut() {
+        if client.gone.is_some() {
+            continue;
+        }
+        if let Some(timestamp) = client.keepalive.ping(now) {
+            pings.push((id, timestamp));
+        }
+        if client.keepalive.dead() {
+            dead.push(id);
+        }
+    }
+    for (id, timestamp) in pings {
+        send_to(clients, id, ToClient::Ping(timestamp));
+    }
+    for id in dead {
+        warn!("Client {} stopped answering pings", id);
+        send_to(clients, id, ToClient::Close);
+        connection_lost(clients, id);
+    }
+}
+
+/// Lets go of the clients which didn't come back within the grace period
+fn expire(clients: &mut HashMap<Uuid, Client>, now: Instant) {
+    let expired: Vec<Uuid> = clients
+        .iter()
+        .filter(|(_, client)| client.gone.is_some_and(|gone| gone + RESUME_GRACE <= now))
+        .map(|(&id, _)| id)
+        .collect();
+    for id in expired {
+        remove_client(clients, id, LeaveReason::Disconnect);
+    }
+}
+
+fn remove_client(clients: &mut HashMap<Uuid, Client>, id: Uuid, reason: LeaveReason) {
+    if let Some(client) = clients.remove(&id) {
+        info!("Client {} left {}: {:?}", id, client.room, reason);
+        broadcast(clients, &client.room, None, &|| ToClient::Left(id, reason));
+    }
+}
+
+/// Puts the client into `room`, announcing it to both the old and the new room.
+fn move_client(clients: &mut HashMap<Uuid, Client>, id: Uuid, room: String) {
+    let (old_room, description) = match clients.get_mut(&id) {
+        Some(client) => (
+            std::mem::replace(&mut client.room, room.clone()),
+            describe_client(id, client),
+        ),
+        None => return,
+    };
+    info!("Client {} moves from {} to {}", id, old_room, room);
+    broadcast(clients, &old_room, Some(id), &|| ToClient::Left(id, LeaveReason::Moved));
+    broadcast(clients, &room, Some(id), &|| ToClient::Joined(description.clone()));
+    send_to(clients, id, ToClient::RoomJoined(room.clone()));
+    let description = describe_clients(clients, &room);
+    send_to(clients, id, ToClient::Clients(description));
 }
 
 fn broadcaster(rx: Receiver<ToBroadcaster>) {
     let mut clients = HashMap::new();
+    let mut connections = HashMap::new();
+    let mut echoes = Echoes::new();
 
-    while let Ok(msg) = rx.recv() {
+    loop {
+        let msg = match next_deadline(&clients, &echoes) {
+            Some(deadline) => {
+                rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => rx.recv().map_err(RecvTimeoutError::from),
+        };
+        let now = Instant::now();
+        while echoes.front().is_some_and(|(due, ..)| *due <= now) {
+            if let Some((_, id, msg)) = echoes.pop_front() {
+                send_to(&mut clients, id, msg);
+            }
+        }
+        expire(&mut clients, now);
+        keep_alive(&mut clients, now);
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
         match msg {
-            ToBroadcaster::NewClient(id, ctx) => {
-                clients.insert(
-                    id,
-                    Client {
-                        nickname: None,
-                        tx: ctx,
-                    },
-                );
+            ToBroadcaster::NewClient {
+                connection,
+                greeting,
+                tx,
+                reply,
+            } => {
+                let admission = admit(&mut clients, &mut connections, connection, greeting, tx);
+                // The connection thread may have given up on the client meanwhile
+                let _ = reply.send(admission);
             }
-            ToBroadcaster::NewPacket(id, packet) => match packet {
-                ClientMsg::GetClients => todo!(),
-                ClientMsg::Nickname(nickname) => {
-                    let mut client = clients.get_mut(&id).expect("No client");
-                    client.nickname = Some(nickname);
-                }
-                ClientMsg::OpusAudio(audio) => {
-                    clients.retain(|&recv_id, client| {
-                        if recv_id == id {
-                            return true;
+            ToBroadcaster::NewPacket(connection, packet) => {
+                let id = match connections.get(&connection) {
+                    Some(&id) => id,
+                    None => continue,
+                };
+                let room = match room_of(&clients, id) {
+                    Some(room) => room,
+                    None => continue,
+                };
+                match packet {
+                    ClientMsg::Hello { .. } => {
+                        warn!("Client {} repeated Hello, ignoring", id);
+                    }
+                    ClientMsg::GetClients => {
+                        let description = describe_clients(&clients, &room);
+                        send_to(&mut clients, id, ToClient::Clients(description));
+                    }
+                    ClientMsg::Nickname(nickname) => {
+                        if !valid_nickname(&nickname) {
+                            warn!("Client {} wants invalid nickname {:?}, ignoring", id, nickname);
+                            continue;
+                        }
+                        if let Some(client) = clients.get_mut(&id) {
+                            client.nickname = Some(nickname.clone());
+                        }
+                        broadcast(&mut clients, &room, None, &|| {
+                            ToClient::NicknameChanged(id, nickname.clone())
+                        });
+                    }
+                    ClientMsg::OpusAudio(frame) => {
+                        if echo(&clients, &mut echoes, id, ToClient::Audio(id, frame.clone())) {
+                            continue;
+                        }
+                        broadcast(&mut clients, &room, Some(id), &|| {
+                            ToClient::Audio(id, frame.clone())
+                        });
+                    }
+                    ClientMsg::EndOfTalk => {
+                        if echo(&clients, &mut echoes, id, ToClient::EndOfTalk(id)) {
+                            continue;
                         }
-                        match client.tx.send(ToClient::Audio(id, audio.clone())) {
-                            Ok(()) => {
-                                true
-                            },
-                            Err(err) => {
-                                warn!("Can't notify client {}, dropping: {}", recv_id, err);
-                                false
-                            }
+                        broadcast(&mut clients, &room, Some(id), &|| ToClient::EndOfTalk(id));
+                    }
+                    ClientMsg::Ping(timestamp) => {
+                        send_to(&mut clients, id, ToClient::Pong(timestamp));
+                    }
+                    ClientMsg::Pong(timestamp) => {
+                        if let Some(client) = clients.get_mut(&id) {
+                            client.keepalive.pong(Instant::now(), timestamp);
                         }
-                    });
+                    }
+                    ClientMsg::EchoTest(delay_ms) => {
+                        let delay_ms = delay_ms.map(|delay_ms| delay_ms.min(MAX_ECHO_DELAY_MS));
+                        info!("Client {} echo test: {:?} ms", id, delay_ms);
+                        let delay = delay_ms.map(|delay_ms| Duration::from_millis(delay_ms as u64));
+                        let was_echoing = match clients.get_mut(&id) {
+                            Some(client) => std::mem::replace(&mut client.echo_delay, delay).is_some(),
+                            None => continue,
+                        };
+                        // The room won't hear the rest of a talk spurt cut short by the test
+                        if delay.is_some() && !was_echoing {
+                            broadcast(&mut clients, &room, Some(id), &|| ToClient::EndOfTalk(id));
+                        }
+                        send_to(&mut clients, id, ToClient::EchoTest(delay_ms));
+                    }
+                    ClientMsg::Status { muted, deafened } => {
+                        if let Some(client) = clients.get_mut(&id) {
+                            client.muted = muted;
+                            client.deafened = deafened;
+                        }
+                        broadcast(&mut clients, &room, None, &|| {
+                            ToClient::StatusChanged(id, muted, deafened)
+                        });
+                    }
+                    ClientMsg::Leave => {
+                        remove_client(&mut clients, id, LeaveReason::Leave);
+                    }
+                    ClientMsg::ListRooms => {
+                        let rooms = describe_rooms(&clients);
+                        send_to(&mut clients, id, ToClient::Rooms(rooms));
+                    }
+                    ClientMsg::CreateRoom(new_room) => {
+                        if !valid_room_name(&new_room) || room_exists(&clients, &new_room) {
+                            let reason = format!("Can't create room {:?}", new_room);
+                            send_to(&mut clients, id, ToClient::RoomError(reason));
+                        } else {
+                            move_client(&mut clients, id, new_room);
+                        }
+                    }
+                    ClientMsg::JoinRoom(new_room) => {
+                        if new_room == room {
+                            continue;
+                        }
+                        if room_exists(&clients, &new_room) {
+                            move_client(&mut clients, id, new_room);
+                        } else {
+                            let reason = format!("No such room {:?}", new_room);
+                            send_to(&mut clients, id, ToClient::RoomError(reason));
+                        }
+                    }
                 }
-                ClientMsg::Leave => {
-                    clients.remove(&id);
+            }
+            ToBroadcaster::ClientGone(connection) => {
+                if let Some(id) = connections.remove(&connection) {
+                    connection_lost(&mut clients, id);
                 }
-            },
-            ToBroadcaster::ClientGone(id) => {
-                clients.remove(&id);
             }
         };
     }
@@ -126,19 +623,72 @@ fn main() -> Result<()> {
         })
         .expect("Can't start broadcaster");
 
-    while let Ok((stream, addr)) = listener.accept() {
-        let id = uuid::Uuid::new_v4();
-        info!("Handling client {} x {}", addr, id);
+    while let Ok((mut stream, addr)) = listener.accept() {
+        let connection = uuid::Uuid::new_v4();
+        info!("Handling connection {} x {}", addr, connection);
 
-        let crtx = btx.clone();
-        let (cwtx, cwrx) = mpsc::channel();
-
-        let stream_read = stream.try_clone().expect("Can't clone stream");
-        std::thread::spawn(move || socket_reader::<_, ClientMsg>(stream_read, Some(id), crtx));
-        std::thread::spawn(move || client_writer(stream, cwrx));
+        let btx = btx.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = write_preamble::<ServerMsg>(&mut stream) {
+                warn!("Dropping connection {}: {:#}", connection, err);
+                return;
+            }
+            stream
+                .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+                .expect("Can't set read timeout");
+            // Audio and pongs go out right away instead of waiting on ACKs
+            stream.set_nodelay(true).expect("Can't disable Nagle");
+            if let Err(err) = read_preamble::<ClientMsg>(&mut stream) {
+                // The client can't understand us, so there is no point in saying bye
+                warn!("Dropping connection {}: {:#}", connection, err);
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+            let greeting = match handshake(&mut stream, connection) {
+                Ok(greeting) => greeting,
+                Err(reason) => {
+                    warn!("Refusing connection {}: {}", connection, reason);
+                    write_msg(&mut stream, ServerMsg::Bye { reason });
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return;
+                }
+            };
+            stream
+                .set_read_timeout(None)
+                .expect("Can't reset read timeout");
 
-        btx.send(ToBroadcaster::NewClient(id, cwtx))
+            let params = greeting.params.clone();
+            let (cwtx, cwrx) = mpsc::channel();
+            let (reply_tx, reply_rx) = mpsc::channel();
+            btx.send(ToBroadcaster::NewClient {
+                connection,
+                greeting,
+                tx: cwtx,
+                reply: reply_tx,
+            })
             .expect("Can't send to broadcaster");
+            let admission = reply_rx.recv().expect("Can't hear back from broadcaster");
+
+            // Goes out before whatever the broadcaster has queued up for the client
+            let msg = ServerMsg::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                uuid: admission.id.into(),
+                params,
+                token: admission.token.into(),
+                resumed: admission.resumed,
+            };
+            if let Err(err) = try_write_msg(&mut stream, msg) {
+                warn!("{:#}", err);
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+
+            let crtx = btx.clone();
+            let stream_read = stream.try_clone().expect("Can't clone stream");
+            std::thread::spawn(move || {
+                socket_reader::<_, ClientMsg>(stream_read, Some(connection), crtx)
+            });
+            std::thread::spawn(move || client_writer(stream, cwrx));
+        });
     }
 
     broadcaster_handle
@@ -149,3 +699,116 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Joined {
+        connection: Uuid,
+        admission: Admission,
+        rx: Receiver<ToClient>,
+    }
+
+    fn join(
+        clients: &mut HashMap<Uuid, Client>,
+        connections: &mut HashMap<Uuid, Uuid>,
+        resume: Option<Uuid>,
+    ) -> Joined {
+        let connection = Uuid::new_v4();
+        let greeting = Greeting {
+            nickname: None,
+            resume,
+            params: SessionParams {
+                codec: Codec::Opus,
+                channels: 1,
+                frame_size: 960,
+            },
+        };
+        let (tx, rx) = mpsc::channel();
+        let admission = admit(clients, connections, connection, greeting, tx);
+        Joined {
+            connection,
+            admission,
+            rx,
+        }
+    }
+
+    #[test]
+    fn resumes_by_token() {
+        let mut clients = HashMap::new();
+        let mut connections = HashMap::new();
+        let first = join(&mut clients, &mut connections, None);
+        let id = first.admission.id;
+        assert!(!first.admission.resumed);
+
+        let second = join(&mut clients, &mut connections, Some(first.admission.token));
+        assert!(second.admission.resumed);
+        assert_eq!(second.admission.id, id);
+        // The token stays valid in case this Welcome gets lost too
+        assert_eq!(second.admission.token, first.admission.token);
+        assert_eq!(connections.get(&second.connection), Some(&id));
+        assert!(!connections.contains_key(&first.connection));
+        assert_eq!(clients.len(), 1);
+        // The old connection may still be half open
+        let seen: Vec<ToClient> = first.rx.try_iter().collect();
+        assert!(matches!(seen.last(), Some(ToClient::Close)));
+    }
+
+    #[test]
+    fn starts_new_session_for_unknown_token() {
+        let mut clients = HashMap::new();
+        let mut connections = HashMap::new();
+        let first = join(&mut clients, &mut connections, None);
+        let second = join(&mut clients, &mut connections, Some(Uuid::new_v4()));
+        assert!(!second.admission.resumed);
+        assert_ne!(second.admission.id, first.admission.id);
+        assert_ne!(second.admission.token, first.admission.token);
+        assert_eq!(clients.len(), 2);
+    }
+
+    #[test]
+    fn room_sees_reconnecting_instead_of_leaving() {
+        let mut clients = HashMap::new();
+        let mut connections = HashMap::new();
+        let peer = join(&mut clients, &mut connections, None);
+        let client = join(&mut clients, &mut connections, None);
+        let id = client.admission.id;
+        peer.rx.try_iter().for_each(drop);
+
+        connection_lost(&mut clients, id);
+        let seen: Vec<ToClient> = peer.rx.try_iter().collect();
+        assert!(matches!(seen[..], [ToClient::Reconnecting(other)] if other == id));
+
+        join(&mut clients, &mut connections, Some(client.admission.token));
+        let seen: Vec<ToClient> = peer.rx.try_iter().collect();
+        assert!(matches!(seen[..], [ToClient::Reconnected(other)] if other == id));
+    }
+
+    #[test]
+    fn removes_after_grace() {
+        let mut clients = HashMap::new();
+        let mut connections = HashMap::new();
+        let peer = join(&mut clients, &mut connections, None);
+        let client = join(&mut clients, &mut connections, None);
+        let id = client.admission.id;
+        connection_lost(&mut clients, id);
+        peer.rx.try_iter().for_each(drop);
+
+        expire(&mut clients, Instant::now());
+        assert!(clients.contains_key(&id));
+        assert!(peer.rx.try_recv().is_err());
+
+        expire(&mut clients, Instant::now() + RESUME_GRACE);
+        assert!(!clients.contains_key(&id));
+        let seen: Vec<ToClient> = peer.rx.try_iter().collect();
+        assert!(matches!(
+            seen[..],
+            [ToClient::Left(other, LeaveReason::Disconnect)] if other == id
+        ));
+
+        // Too late to resume, it's a new session
+        let late = join(&mut clients, &mut connections, Some(client.admission.token));
+        assert!(!late.admission.resumed);
+    }
+}
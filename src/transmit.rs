@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use anyhow::Result;
+use audiopus::coder::Encoder;
+use discurse::protocol::AudioFrame;
+
+use crate::{
+    codec::{opus_channels, CodecOptions},
+    controls::Controls,
+    vad::Vad,
+};
+
+/// Largest packet a single Opus call produces, as recommended by libopus
+const MAX_PACKET: usize = 4000;
+
+/// What the transmitter wants sent for the audio it was given
+pub enum Outgoing {
+    Audio(AudioFrame),
+    /// Talk spurt is over, nothing follows until the next one
+    EndOfTalk,
+}
+
+/// Cuts captured audio into frames and encodes the ones which should go out,
+/// deciding by the transmit mode and voice activity. The server connection and
+/// the loopback both send through it.
+pub struct Transmitter {
+    encoder: Encoder,
+    /// Samples per channel
+    frame_size: usize,
+    channels: usize,
+    dtx: bool,
+    vad: Vad,
+    controls: Arc<Controls>,
+    talking: bool,
+    captured: VecDeque<f32>,
+    seq: u32,
+    timestamp: u64,
+    packet: Vec<u8>,
+}
+
+impl Transmitter {
+    pub fn new(
+        codec: &CodecOptions,
+        channels: usize,
+        frame_size: usize,
+        controls: Arc<Controls>,
+    ) -> Result<Self> {
+        Ok(Self {
+            encoder: codec.encoder(opus_channels(channels))?,
+            frame_size,
+            channels,
+            dtx: codec.encoder.dtx,
+            vad: Vad::new(),
+            controls,
+            talking: false,
+            captured: VecDeque::new(),
+            seq: 0,
+            timestamp: 0,
+            packet: vec![0; MAX_PACKET],
+        })
+    }
+
+    /// Takes interleaved audio from the mic, returns what to send for every
+    /// frame it completes
+    pub fn push(&mut self, audio: &[f32]) -> Vec<Outgoing> {
+        self.captured.extend(audio);
+        let channels = self.channels;
+        let mut outgoing = vec![];
+        while self.captured.len() >= self.frame_size * channels {
+            let for_opus: Vec<f32> = self.captured.drain(..self.frame_size * channels).collect();
+
+            // The timestamp runs on through silence so that
+            // receivers can tell how long the pause was
+            let frame_timestamp = self.timestamp;
+            self.timestamp += self.frame_size as u64;
+
+            let mono: Vec<f32> = for_opus
+                .chunks(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+                .collect();
+            let speech = self.vad.process(&mono);
+            if !self.controls.should_transmit(speech) {
+                if self.talking {
+                    self.talking = false;
+                    outgoing.push(Outgoing::EndOfTalk);
+                }
+                continue;
+            }
+            self.talking = true;
+
+            let len = self
+                .encoder
+                .encode_float(&for_opus, &mut self.packet)
+                .expect("Can't encode");
+            // With DTX, frames of up to two bytes carry no audio
+            if self.dtx && len <= 2 {
+                continue;
+            }
+
+            outgoing.push(Outgoing::Audio(AudioFrame {
+                seq: self.seq,
+                timestamp: frame_timestamp,
+                duration: self.frame_size as u32,
+                data: self.packet[..len].to_vec(),
+            }));
+            self.seq = self.seq.wrapping_add(1);
+        }
+        outgoing
+    }
+
+    /// Forgets the talk spurt in progress, whoever was listening lost track of it
+    pub fn interrupt(&mut self) {
+        self.talking = false;
+    }
+}
@@ -0,0 +1,96 @@
+use std::f32::consts::PI;
+
+use crate::fft::Fft;
+
+/// Analysis block, 10 ms at 48 kHz
+const BLOCK: usize = 480;
+const FFT_SIZE: usize = 512;
+/// Band the spectral flatness is measured over, where voiced speech has its harmonics
+const LOW_BIN: usize = FFT_SIZE * 300 / 48000;
+const HIGH_BIN: usize = FFT_SIZE * 4000 / 48000;
+/// Nothing quieter than this counts as speech
+const MIN_SPEECH_DBFS: f32 = -60.0;
+/// Margin over the noise floor of tonal, speech-like blocks
+const SPEECH_MARGIN_DB: f32 = 6.0;
+/// Margin over the noise floor above which anything counts as speech, e.g. fricatives
+const LOUD_MARGIN_DB: f32 = 15.0;
+/// Spectral flatness below which a block is tonal rather than noise-like
+const MAX_SPEECH_FLATNESS: f32 = 0.4;
+/// Per-block rise of the noise floor estimate, 2 dB per second
+const FLOOR_RISE_DB: f32 = 0.02;
+/// How long transmission goes on after the last speech, 300 ms
+const HANGOVER_BLOCKS: u32 = 30;
+
+/// Energy and spectral flatness voice activity detector with hangover, so
+/// that short pauses and word endings don't cut the transmission
+pub struct Vad {
+    fft: Fft,
+    window: Vec<f32>,
+    block: Vec<f32>,
+    /// Noise floor estimate, dBFS
+    floor_db: Option<f32>,
+    /// Blocks left until the detector falls silent
+    hangover: u32,
+}
+
+impl Vad {
+    pub fn new() -> Self {
+        Self {
+            fft: Fft::new(FFT_SIZE),
+            window: (0..BLOCK)
+                .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / BLOCK as f32).cos())
+                .collect(),
+            block: Vec::with_capacity(BLOCK),
+            floor_db: None,
+            hangover: 0,
+        }
+    }
+
+    /// Feeds mono 48 kHz audio, returns whether there's speech in it or just before it
+    pub fn process(&mut self, buf: &[f32]) -> bool {
+        for &smp in buf {
+            self.block.push(smp);
+            if self.block.len() == BLOCK {
+                if self.is_speech() {
+                    self.hangover = HANGOVER_BLOCKS;
+                } else {
+                    self.hangover = self.hangover.saturating_sub(1);
+                }
+                self.block.clear();
+            }
+        }
+        self.hangover > 0
+    }
+
+    fn is_speech(&mut self) -> bool {
+        let power = self.block.iter().map(|smp| smp * smp).sum::<f32>() / BLOCK as f32;
+        let level_db = 10.0 * power.max(1e-12).log10();
+        let floor_db = match self.floor_db {
+            Some(floor_db) => level_db.min(floor_db + FLOOR_RISE_DB),
+            None => level_db,
+        };
+        self.floor_db = Some(floor_db);
+
+        if level_db < MIN_SPEECH_DBFS || level_db < floor_db + SPEECH_MARGIN_DB {
+            return false;
+        }
+        level_db > floor_db + LOUD_MARGIN_DB || self.flatness() < MAX_SPEECH_FLATNESS
+    }
+
+    /// Geometric over arithmetic mean of the power spectrum, near 0 for tones and 0.56 for white noise
+    fn flatness(&self) -> f32 {
+        let mut re = vec![0.0; FFT_SIZE];
+        let mut im = vec![0.0; FFT_SIZE];
+        for (re, (smp, window)) in re.iter_mut().zip(self.block.iter().zip(&self.window)) {
+            *re = smp * window;
+        }
+        self.fft.forward(&mut re, &mut im);
+
+        let bins = HIGH_BIN - LOW_BIN;
+        let (log_sum, sum) = (LOW_BIN..HIGH_BIN).fold((0.0, 0.0), |(log_sum, sum), k| {
+            let power = re[k] * re[k] + im[k] * im[k] + 1e-12;
+            (log_sum + power.ln(), sum + power)
+        });
+        (log_sum / bins as f32).exp() / (sum / bins as f32)
+    }
+}
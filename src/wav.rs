@@ -0,0 +1,188 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+/// Sample rate and channel count of interleaved audio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// Size of the header `WavWriter` writes before the data
+const HEADER_LEN: u32 = 44;
+
+/// Reads a 16-bit integer or 32-bit float WAV file into interleaved samples
+pub fn read(path: &Path) -> Result<(Format, Vec<f32>)> {
+    let bytes = fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+    parse(&bytes).with_context(|| format!("Can't parse {}", path.display()))
+}
+
+fn parse(bytes: &[u8]) -> Result<(Format, Vec<f32>)> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        bail!("Not a WAV file");
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    let u32_at =
+        |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+
+    let mut format = None;
+    let mut chunk = 12;
+    while chunk + 8 <= bytes.len() {
+        let id = &bytes[chunk..chunk + 4];
+        let len = u32_at(chunk + 4) as usize;
+        let body = chunk + 8;
+        let end = (body + len).min(bytes.len());
+        match id {
+            b"fmt " if len >= 16 => {
+                // Sizes in the header may promise more than the file holds
+                if end - body < len.min(26) {
+                    bail!("Truncated fmt chunk");
+                }
+                let mut tag = u16_at(body);
+                if tag == FORMAT_EXTENSIBLE && len >= 26 {
+                    // The subformat GUID starts with the actual format tag
+                    tag = u16_at(body + 24);
+                }
+                format = Some((tag, u16_at(body + 2), u32_at(body + 4), u16_at(body + 14)));
+            }
+            b"data" => {
+                let (tag, channels, sample_rate, bits) = format.context("Data comes before fmt")?;
+                if channels == 0 {
+                    bail!("No channels");
+                }
+                let data = &bytes[body..end];
+                let samples = match (tag, bits) {
+                    (FORMAT_PCM, 16) => data
+                        .chunks_exact(2)
+                        .map(|smp| i16::from_le_bytes([smp[0], smp[1]]) as f32 / i16::MAX as f32)
+                        .collect(),
+                    (FORMAT_FLOAT, 32) => data
+                        .chunks_exact(4)
+                        .map(|smp| f32::from_le_bytes([smp[0], smp[1], smp[2], smp[3]]))
+                        .collect(),
+                    _ => bail!(
+                        "Unsupported format {} with {} bits, expected 16-bit PCM or 32-bit float",
+                        tag,
+                        bits
+                    ),
+                };
+                let format = Format {
+                    sample_rate,
+                    channels: channels as usize,
+                };
+                return Ok((format, samples));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        chunk = body + len + len % 2;
+    }
+    bail!("No data chunk")
+}
+
+/// Streams interleaved samples into a 16-bit WAV file, sizes being filled in by `finish`
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, format: Format) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            data_len: 0,
+        };
+        let channels = format.channels as u16;
+        let mut header = vec![];
+        header.extend(b"RIFF");
+        header.extend((HEADER_LEN - 8).to_le_bytes());
+        header.extend(b"WAVEfmt ");
+        header.extend(16u32.to_le_bytes());
+        header.extend(FORMAT_PCM.to_le_bytes());
+        header.extend(channels.to_le_bytes());
+        header.extend(format.sample_rate.to_le_bytes());
+        header.extend((format.sample_rate * 2 * channels as u32).to_le_bytes());
+        header.extend((2 * channels).to_le_bytes());
+        header.extend(16u16.to_le_bytes());
+        header.extend(b"data");
+        header.extend(0u32.to_le_bytes());
+        writer.file.write_all(&header)?;
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let data_len = u32::try_from(2 * samples.len())
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|data_len| data_len.checked_add(HEADER_LEN - 8).is_some())
+            .context("WAV file is full, it can't get past 4 GiB")?;
+        for smp in samples {
+            let smp = (smp.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&smp.to_le_bytes())?;
+        }
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    /// Fills in the sizes left blank in the header
+    pub fn finish(mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes interleaved samples into a 16-bit WAV file at once
+#[cfg(test)]
+pub fn write(path: &Path, format: Format, samples: &[f32]) -> Result<()> {
+    let mut writer = WavWriter::create(path, format)?;
+    writer.write(samples)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_truncated_fmt() {
+        let mut bytes = vec![];
+        bytes.extend(b"RIFF");
+        bytes.extend(36u32.to_le_bytes());
+        bytes.extend(b"WAVEfmt ");
+        bytes.extend(40u32.to_le_bytes());
+        bytes.extend(FORMAT_EXTENSIBLE.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        for len in 12..bytes.len() + 20 {
+            bytes.resize(len, 0);
+            assert!(parse(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn stops_before_4_gib() {
+        let path = std::env::temp_dir().join(format!("discurse-full-{}.wav", std::process::id()));
+        let format = Format {
+            sample_rate: 48000,
+            channels: 1,
+        };
+        let mut writer = WavWriter::create(&path, format).expect("Can't create WAV");
+        // Room for two more samples
+        writer.data_len = u32::MAX - (HEADER_LEN - 8) - 4;
+        assert!(writer.write(&[0.0; 2]).is_ok());
+        assert!(writer.write(&[0.0; 2]).is_err());
+        fs::remove_file(&path).expect("Can't remove WAV");
+    }
+}